@@ -1,9 +1,11 @@
 {
 	"api_port": 7101,
+	"api_threads": 4,
 	"server_cert": "{{sysconfdir}}/intecture/agent.crt",
 	"filexfer_port": 7102,
 	"filexfer_threads": 2,
 	"auth_server": "127.0.0.1",
 	"auth_update_port": 7104,
-	"auth_cert": "{{sysconfdir}}/intecture/auth.crt_public"
+	"auth_cert": "{{sysconfdir}}/intecture/auth.crt_public",
+	"job_retention": 3600
 }
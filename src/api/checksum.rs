@@ -0,0 +1,135 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use error::{Error, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl Algorithm {
+    /// Parses an algorithm name, defaulting to SHA-256 if empty.
+    pub fn parse(name: &str) -> Result<Algorithm> {
+        match name.to_lowercase().replace("-", "").as_str() {
+            "" | "sha256" => Ok(Algorithm::Sha256),
+            "sha1" => Ok(Algorithm::Sha1),
+            "md5" => Ok(Algorithm::Md5),
+            _ => Err(Error::InvalidArg(format!("Unknown checksum algorithm: {}", name))),
+        }
+    }
+
+    fn digest(&self) -> Box<Digest> {
+        match *self {
+            Algorithm::Md5 => Box::new(Md5::new()),
+            Algorithm::Sha1 => Box::new(Sha1::new()),
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+        }
+    }
+}
+
+/// Hex digest of a file's contents.
+pub fn file(path: &Path, algorithm: Algorithm) -> Result<String> {
+    let mut fh = fs::File::open(path)?;
+    let mut digest = algorithm.digest();
+    let mut buf = [0; 65536];
+    loop {
+        let len = fh.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        digest.input(&buf[..len]);
+    }
+    Ok(digest.result_str())
+}
+
+/// Digests of every regular file below `path`, keyed by relative path,
+/// and a rollup digest over the whole manifest. Symlinks are not
+/// followed.
+///
+/// The rollup is the digest of the manifest in `sha256sum` format
+/// ("<digest>  <path>\n" per file, sorted by path), so it changes if
+/// any file is added, removed, renamed or modified.
+pub fn tree(path: &Path, algorithm: Algorithm) -> Result<(String, BTreeMap<String, String>)> {
+    let mut manifest = BTreeMap::new();
+    walk(path, path, algorithm, &mut manifest)?;
+
+    let mut digest = algorithm.digest();
+    for (name, sum) in &manifest {
+        digest.input_str(&format!("{}  {}\n", sum, name));
+    }
+    Ok((digest.result_str(), manifest))
+}
+
+fn walk(root: &Path, dir: &Path, algorithm: Algorithm, manifest: &mut BTreeMap<String, String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(root, &path, algorithm, manifest)?;
+        } else if file_type.is_file() {
+            let name = path.strip_prefix(root).unwrap().to_string_lossy().into_owned();
+            manifest.insert(name, file(&path, algorithm)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Algorithm::parse("").unwrap(), Algorithm::Sha256);
+        assert_eq!(Algorithm::parse("SHA-1").unwrap(), Algorithm::Sha1);
+        assert_eq!(Algorithm::parse("md5").unwrap(), Algorithm::Md5);
+        assert!(Algorithm::parse("crc32").is_err());
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = TempDir::new("test_checksum_file").unwrap();
+        let path = dir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+
+        assert_eq!(file(&path, Algorithm::Md5).unwrap(), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(file(&path, Algorithm::Sha1).unwrap(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(file(&path, Algorithm::Sha256).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_tree() {
+        let dir = TempDir::new("test_checksum_tree").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::File::create(dir.path().join("a")).unwrap().write_all(b"abc").unwrap();
+        fs::File::create(dir.path().join("sub/b")).unwrap().write_all(b"abc").unwrap();
+
+        let (rollup, manifest) = tree(dir.path(), Algorithm::Md5).unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest["a"], "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(manifest["sub/b"], "900150983cd24fb0d6963f7d28e17f72");
+
+        fs::File::create(dir.path().join("sub/b")).unwrap().write_all(b"abcd").unwrap();
+        assert!(tree(dir.path(), Algorithm::Md5).unwrap().0 != rollup);
+    }
+}
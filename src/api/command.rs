@@ -6,28 +6,148 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+use config::Limits;
 use czmq::{ZMsg, ZSock};
 use error::{Error, Result};
 use inapi::{Command, Host};
+use serde_json;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use super::policy::Policy;
+use super::process::{self, ExecOpts};
 use zdaemon::ZMsgExtended;
 
-pub struct CommandApi;
+pub struct CommandApi {
+    host: Rc<RefCell<Host>>,
+    limits: Limits,
+    policy: Arc<Policy>,
+}
 
 impl CommandApi {
-    pub fn exec(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
-        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
-        let cmd = Command::new(&request.popstr().unwrap().or(Err(Error::MessageUtf8))?);
-        let result = cmd.exec(host)?;
+    pub fn new(host: Rc<RefCell<Host>>, limits: Limits, policy: Arc<Policy>) -> CommandApi {
+        CommandApi {
+            host: host,
+            limits: limits,
+            policy: policy,
+        }
+    }
+
+    // Request frames are the command, then optionally a JSON encoded
+    // `ExecOpts` and a stdin payload.
+    pub fn exec(&self, sock: &mut ZSock, user_id: Option<&str>, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(3), false)?;
+        let cmd = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        self.policy.check(user_id, &cmd)?;
+
+        // Requests without an options frame keep the original behaviour,
+        // unless there are default limits that need applying.
+        let mut opts: ExecOpts = match request.popstr() {
+            Some(json) => serde_json::from_str(&json.or(Err(Error::MessageUtf8))?)?,
+            None if self.limits.is_empty() => {
+                let result = Command::new(&cmd).exec(&mut self.host.borrow_mut())?;
+                let msg = ZMsg::new_ok()?;
+                msg.pushstr("")?;
+                msg.pushbytes(router_id)?;
+                msg.send_multi(sock, &[
+                    &result.exit_code.to_string(),
+                    &result.stdout,
+                    &result.stderr
+                ])?;
+                return Ok(());
+            },
+            None => ExecOpts::default(),
+        };
+        opts.limits = opts.limits.merge(&self.limits);
+
+        let stdin = request.popbytes()?;
+        if let Some(ref id) = opts.stream {
+            return Self::exec_stream(sock, router_id, &cmd, &opts, stdin, id);
+        }
+
+        let output = process::exec(&cmd, &opts, stdin)?;
+        let msg = if output.timed_out {
+            let msg = ZMsg::new();
+            msg.addstr("Timeout")?;
+            msg
+        } else {
+            let msg = ZMsg::new_ok()?;
+            msg.addstr(&output.exit_code.to_string())?;
+            msg
+        };
+        let mut frames = vec![output.stdout, output.stderr];
+        if let Some(c) = output.combined {
+            frames.push(c);
+        }
+        for frame in frames {
+            if opts.binary == Some(true) {
+                msg.addbytes(&frame)?;
+            } else {
+                msg.addstr(&String::from_utf8_lossy(&frame))?;
+            }
+        }
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.send(sock)?;
+
+        Ok(())
+    }
+
+    // Request frames are the program followed by each of its arguments
+    pub fn exec_argv(&self, sock: &mut ZSock, user_id: Option<&str>, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, None, false)?;
+        let mut argv = Vec::new();
+        while let Some(arg) = request.popstr() {
+            argv.push(arg.or(Err(Error::MessageUtf8))?);
+        }
+
+        self.policy.check(user_id, &argv.join(" "))?;
+
+        let mut opts = ExecOpts::default();
+        opts.limits = self.limits.clone();
+        let output = process::exec_argv(&argv, &opts, None)?;
 
         let msg = ZMsg::new_ok()?;
         msg.pushstr("")?;
         msg.pushbytes(router_id)?;
         msg.send_multi(sock, &[
-            &result.exit_code.to_string(),
-            &result.stdout,
-            &result.stderr
+            &output.exit_code.to_string(),
+            &String::from_utf8_lossy(&output.stdout),
+            &String::from_utf8_lossy(&output.stderr)
         ])?;
+        Ok(())
+    }
+
+    // Each chunk of output is sent as a "Chunk" message carrying the
+    // request id and stream name. The final message carries the
+    // request id and exit code, or is a "Timeout" message.
+    fn exec_stream(sock: &mut ZSock, router_id: &[u8], cmd: &str, opts: &ExecOpts, stdin: Option<Vec<u8>>, id: &str) -> Result<()> {
+        let (exit_code, timed_out) = process::exec_stream(cmd, opts, stdin, |stream, chunk| {
+            let msg = ZMsg::new();
+            msg.addstr("Chunk")?;
+            msg.addstr(id)?;
+            msg.addstr(stream.name())?;
+            msg.addbytes(chunk)?;
+            msg.pushstr("")?;
+            msg.pushbytes(router_id)?;
+            msg.send(sock)?;
+            Ok(())
+        })?;
 
+        let msg = if timed_out {
+            let msg = ZMsg::new();
+            msg.addstr("Timeout")?;
+            msg.addstr(id)?;
+            msg
+        } else {
+            let msg = ZMsg::new_ok()?;
+            msg.addstr(id)?;
+            msg.addstr(&exit_code.to_string())?;
+            msg
+        };
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.send(sock)?;
         Ok(())
     }
 }
@@ -8,9 +8,15 @@
 
 use czmq::{ZMsg, ZSock};
 use error::{Error, Result};
+use glob::Pattern;
 use inapi::{Directory, DirectoryOpts, Host};
+use serde_json;
 use std::cell::RefCell;
+use std::{fs, io};
+use std::path::Path;
 use std::rc::Rc;
+use super::checksum::{self, Algorithm};
+use super::stat::Stat;
 use zdaemon::ZMsgExtended;
 
 pub struct DirectoryApi {
@@ -149,4 +155,143 @@ impl DirectoryApi {
         msg.send(sock)?;
         Ok(())
     }
+
+    // Frames: path, [algorithm]. Replies with the rollup checksum and a
+    // JSON manifest of each file's checksum, keyed by relative path.
+    pub fn checksum_tree(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(2), false)?;
+        let path = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let algorithm = match request.popstr() {
+            Some(a) => Algorithm::parse(&a.or(Err(Error::MessageUtf8))?)?,
+            None => Algorithm::Sha256,
+        };
+        Directory::new(&mut self.host.borrow_mut(), &path)?;
+        let (rollup, manifest) = checksum::tree(Path::new(&path), algorithm)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&rollup)?;
+        msg.addstr(&serde_json::to_string(&manifest)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    // Replies with the path's `Stat` as JSON.
+    pub fn stat(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let path = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        Directory::new(&mut self.host.borrow_mut(), &path)?;
+        let stat = Stat::new(Path::new(&path))?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&stat)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    // Frames: path, [depth], [glob], [hidden]. Depth defaults to 1,
+    // i.e. immediate children only, and "0" means unlimited. The glob
+    // is matched against entry names. Hidden entries are skipped unless
+    // hidden is "1". Replies with a JSON array of `Stat`s.
+    pub fn list(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(4), false)?;
+        let path = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let depth = match request.popstr() {
+            Some(d) => {
+                let d = d.or(Err(Error::MessageUtf8))?;
+                if d.is_empty() { Some(1) } else {
+                    match d.parse::<usize>()? {
+                        0 => None,
+                        d => Some(d),
+                    }
+                }
+            },
+            None => Some(1),
+        };
+        let pattern = match request.popstr() {
+            Some(g) => {
+                let g = g.or(Err(Error::MessageUtf8))?;
+                if g.is_empty() { None } else { Some(Pattern::new(&g)?) }
+            },
+            None => None,
+        };
+        let hidden = match request.popstr() {
+            Some(h) => h.or(Err(Error::MessageUtf8))? == "1",
+            None => false,
+        };
+
+        Directory::new(&mut self.host.borrow_mut(), &path)?;
+        let mut entries = Vec::new();
+        list(Path::new(&path), depth, pattern.as_ref(), hidden, &mut entries)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&entries)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+}
+
+// Symlinked directories are listed but not descended into, so links
+// can't send us round in circles.
+fn list(dir: &Path, depth: Option<usize>, pattern: Option<&Pattern>, hidden: bool, entries: &mut Vec<Stat>) -> Result<()> {
+    let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|c| c.file_name());
+
+    for child in children {
+        let name = child.file_name().to_string_lossy().into_owned();
+        if !hidden && name.starts_with('.') {
+            continue;
+        }
+
+        let path = child.path();
+        if pattern.map(|p| p.matches(&name)).unwrap_or(true) {
+            entries.push(Stat::new(&path)?);
+        }
+
+        if child.file_type()?.is_dir() {
+            match depth {
+                Some(d) if d > 1 => list(&path, Some(d - 1), pattern, hidden, entries)?,
+                None => list(&path, None, pattern, hidden, entries)?,
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use glob::Pattern;
+    use std::fs;
+    use super::list;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_list() {
+        let dir = TempDir::new("test_list").unwrap();
+        fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        fs::File::create(dir.path().join("a/b/c.txt")).unwrap();
+        fs::File::create(dir.path().join("a/d.log")).unwrap();
+        fs::File::create(dir.path().join(".hidden")).unwrap();
+
+        let names = |depth, pattern: Option<&str>, hidden| {
+            let pattern = pattern.map(|p| Pattern::new(p).unwrap());
+            let mut entries = Vec::new();
+            list(dir.path(), depth, pattern.as_ref(), hidden, &mut entries).unwrap();
+            entries.into_iter()
+                   .map(|e| e.path[dir.path().to_str().unwrap().len() + 1..].to_owned())
+                   .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(Some(1), None, false), vec!["a"]);
+        assert_eq!(names(Some(1), None, true), vec![".hidden", "a"]);
+        assert_eq!(names(Some(2), None, false), vec!["a", "a/b", "a/d.log"]);
+        assert_eq!(names(None, None, false), vec!["a", "a/b", "a/b/c.txt", "a/d.log"]);
+        assert_eq!(names(None, Some("*.txt"), false), vec!["a/b/c.txt"]);
+    }
 }
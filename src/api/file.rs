@@ -9,8 +9,20 @@
 use czmq::{ZMsg, ZSock};
 use error::{Error, Result};
 use inapi::{File, Host};
+use libc;
+use serde_json;
 use std::cell::RefCell;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
 use std::rc::Rc;
+use super::checksum::{self, Algorithm};
+use super::stat::Stat;
+use super::process;
+use super::write_if_changed_as;
 use zdaemon::ZMsgExtended;
 
 pub struct FileApi {
@@ -131,4 +143,215 @@ impl FileApi {
         msg.send(sock)?;
         Ok(())
     }
+
+    // Frames: path, [offset], [length]. Omitted or empty offset and
+    // length frames read from the start and to the end of the file
+    // respectively. Replies with the contents as a single byte frame.
+    pub fn read(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(3), false)?;
+        let path = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let offset = match request.popstr() {
+            Some(o) => {
+                let o = o.or(Err(Error::MessageUtf8))?;
+                if o.is_empty() { 0 } else { o.parse()? }
+            },
+            None => 0,
+        };
+        let length = match request.popstr() {
+            Some(l) => {
+                let l = l.or(Err(Error::MessageUtf8))?;
+                if l.is_empty() { None } else { Some(l.parse()?) }
+            },
+            None => None,
+        };
+
+        File::new(&mut self.host.borrow_mut(), &path)?;
+        let contents = read_range(Path::new(&path), offset, length)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addbytes(&contents)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    // Frames: path, contents, [mode], [user, group]. The mode is octal,
+    // e.g. "644". Replies "1" if the contents changed, otherwise "0".
+    pub fn write(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 2, Some(5), false)?;
+        let path = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let contents = request.popbytes()?.unwrap();
+        let mode = match request.popstr() {
+            Some(m) => {
+                let m = m.or(Err(Error::MessageUtf8))?;
+                if m.is_empty() { None } else { Some(u32::from_str_radix(&m, 8)?) }
+            },
+            None => None,
+        };
+        let owner = match (request.popstr(), request.popstr()) {
+            (Some(u), Some(g)) => Some((u.or(Err(Error::MessageUtf8))?, g.or(Err(Error::MessageUtf8))?)),
+            (Some(_), None) => return Err(Error::InvalidArg("Missing group".into())),
+            _ => None,
+        };
+
+        let owner = match owner {
+            Some((user, group)) => Some((process::lookup_user(&user)?.uid, process::lookup_group(&group)?)),
+            None => None,
+        };
+        let changed = write_contents(Path::new(&path), &contents, mode, owner)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if changed { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    // Frames: path, [algorithm]. Defaults to SHA-256.
+    pub fn checksum(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(2), false)?;
+        let path = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let algorithm = match request.popstr() {
+            Some(a) => Algorithm::parse(&a.or(Err(Error::MessageUtf8))?)?,
+            None => Algorithm::Sha256,
+        };
+        File::new(&mut self.host.borrow_mut(), &path)?;
+        let sum = checksum::file(Path::new(&path), algorithm)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&sum)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    // Replies with the path's `Stat` as JSON.
+    pub fn stat(&self, sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let path = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        File::new(&mut self.host.borrow_mut(), &path)?;
+        let stat = Stat::new(Path::new(&path))?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&stat)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+}
+
+// Unless overridden, a replaced file keeps its mode and owner. Symlinks
+// are resolved so the target is replaced rather than the link itself,
+// except for dangling links, which are replaced.
+fn write_contents(path: &Path, contents: &[u8], mode: Option<u32>, owner: Option<(libc::uid_t, libc::gid_t)>) -> Result<bool> {
+    let target = match fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => path.to_owned(),
+        Err(e) => return Err(e.into()),
+    };
+    let existing = fs::metadata(&target).ok();
+    let changed = write_if_changed_as(&target, contents, Some(match (mode, existing.as_ref()) {
+        (Some(m), _) => m,
+        (None, Some(meta)) => meta.permissions().mode() & 0o7777,
+        (None, None) => 0o644,
+    }), owner.or(existing.as_ref().map(|m| (m.uid(), m.gid()))))?;
+
+    // The contents already match, but the metadata may not. Each of
+    // these takes effect in one step.
+    if !changed {
+        if let Some((uid, gid)) = owner {
+            chown(&target, uid, gid)?;
+        }
+        if let Some(m) = mode {
+            fs::set_permissions(&target, fs::Permissions::from_mode(m))?;
+        }
+    }
+
+    Ok(changed)
+}
+
+fn chown(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).or(Err(Error::InvalidArg(format!("Invalid path: {}", path.display()))))?;
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error().into())
+    }
+}
+
+fn read_range(path: &Path, offset: u64, length: Option<u64>) -> Result<Vec<u8>> {
+    let mut fh = fs::File::open(path)?;
+    fh.seek(SeekFrom::Start(offset))?;
+
+    let mut contents = Vec::new();
+    match length {
+        Some(l) => fh.take(l).read_to_end(&mut contents)?,
+        None => fh.read_to_end(&mut contents)?,
+    };
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use libc;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+    use super::{chown, read_range, write_contents};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_read_range() {
+        let dir = TempDir::new("test_read_range").unwrap();
+        let path = dir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc\x00\xffdef").unwrap();
+
+        assert_eq!(read_range(&path, 0, None).unwrap(), b"abc\x00\xffdef");
+        assert_eq!(read_range(&path, 3, Some(2)).unwrap(), b"\x00\xff");
+        assert_eq!(read_range(&path, 5, Some(100)).unwrap(), b"def");
+        assert!(read_range(&path, 100, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_contents() {
+        let dir = TempDir::new("test_write_contents").unwrap();
+        let path = dir.path().join("file");
+
+        assert!(write_contents(&path, b"one", None, None).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o644);
+        assert!(!write_contents(&path, b"one", None, None).unwrap());
+
+        // Replacing keeps the existing mode and owner
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        if unsafe { libc::geteuid() } == 0 {
+            chown(&path, 1, 1).unwrap();
+        }
+        let before = fs::metadata(&path).unwrap();
+        assert!(write_contents(&path, b"two", None, None).unwrap());
+        let after = fs::metadata(&path).unwrap();
+        assert_eq!(after.permissions().mode() & 0o7777, 0o600);
+        assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()));
+        assert!(after.ino() != before.ino());
+
+        // Metadata is still applied if the contents match
+        assert!(!write_contents(&path, b"two", Some(0o640), None).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    fn test_write_contents_symlink() {
+        let dir = TempDir::new("test_write_contents_symlink").unwrap();
+        let path = dir.path().join("file");
+        let link = dir.path().join("link");
+        fs::File::create(&path).unwrap().write_all(b"one").unwrap();
+        symlink(&path, &link).unwrap();
+
+        assert!(write_contents(&link, b"two", None, None).unwrap());
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(read_range(&path, 0, None).unwrap(), b"two");
+    }
 }
@@ -0,0 +1,364 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use config::Limits;
+use czmq::{ZMsg, ZSock};
+use error::{Error, Result};
+use serde_json;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use super::policy::Policy;
+use super::process::{self, ExecOpts, Stream};
+use zdaemon::ZMsgExtended;
+
+/// Longest a `job::wait` request may block for, in seconds
+const MAX_WAIT: u64 = 300;
+/// Most output kept for each of a job's stdout and stderr, in bytes.
+/// Anything beyond this is discarded.
+const MAX_OUTPUT: usize = 16 * 1024 * 1024;
+
+struct Job {
+    command: String,
+    // The ZAP user id of the client that started the job, which is the
+    // only client that can see or kill it
+    owner: Option<String>,
+    started: SystemTime,
+    state: Mutex<JobState>,
+    done: Condvar,
+    cancel: AtomicBool,
+}
+
+#[derive(Default)]
+struct JobState {
+    finished: Option<SystemTime>,
+    exit_code: Option<i32>,
+    timed_out: bool,
+    error: Option<String>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct JobStatus {
+    id: u64,
+    command: String,
+    state: &'static str,
+    exit_code: Option<i32>,
+    error: Option<String>,
+    started: u64,
+    finished: Option<u64>,
+    truncated: bool,
+}
+
+pub struct JobApi {
+    jobs: Mutex<HashMap<u64, Arc<Job>>>,
+    next_id: AtomicUsize,
+    retention: Duration,
+    limits: Limits,
+    policy: Arc<Policy>,
+}
+
+impl JobApi {
+    pub fn new(retention: u64, limits: Limits, policy: Arc<Policy>) -> JobApi {
+        JobApi {
+            jobs: Mutex::new(HashMap::new()),
+            next_id: AtomicUsize::new(1),
+            retention: Duration::from_secs(retention),
+            limits: limits,
+            policy: policy,
+        }
+    }
+
+    // Takes the same frames as `command::exec`, though output is never
+    // streamed.
+    pub fn start(&self, sock: &mut ZSock, user_id: Option<&str>, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(3), false)?;
+        let cmd = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        self.policy.check(user_id, &cmd)?;
+        let mut opts: ExecOpts = match request.popstr() {
+            Some(json) => serde_json::from_str(&json.or(Err(Error::MessageUtf8))?)?,
+            None => ExecOpts::default(),
+        };
+        opts.limits = opts.limits.merge(&self.limits);
+        let stdin = request.popbytes()?;
+
+        let (id, _) = self.spawn(cmd, opts, stdin, user_id.map(|u| u.to_string()));
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&id.to_string())?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn status(&self, sock: &mut ZSock, user_id: Option<&str>, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let id = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let (id, job) = self.get(&id, user_id)?;
+        send_status(sock, router_id, id, &job)
+    }
+
+    pub fn output(&self, sock: &mut ZSock, user_id: Option<&str>, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let id = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let (_, job) = self.get(&id, user_id)?;
+        let state = job.state.lock().unwrap();
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addbytes(&state.stdout)?;
+        msg.addbytes(&state.stderr)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    // Blocks until the job finishes or the timeout (in seconds) passes,
+    // then replies with the job's status. The timeout is capped at
+    // `MAX_WAIT` so a job can't tie up a worker indefinitely; clients
+    // should wait again while the job is still running.
+    pub fn wait(&self, sock: &mut ZSock, user_id: Option<&str>, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(2), false)?;
+        let id = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let timeout = match request.popstr() {
+            Some(t) => t.or(Err(Error::MessageUtf8))?.parse::<u64>()?.min(MAX_WAIT),
+            None => MAX_WAIT,
+        };
+        let (id, job) = self.get(&id, user_id)?;
+        wait(&job, Duration::from_secs(timeout));
+        send_status(sock, router_id, id, &job)
+    }
+
+    pub fn kill(&self, sock: &mut ZSock, user_id: Option<&str>, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let id = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let (_, job) = self.get(&id, user_id)?;
+        let running = cancel(&job);
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if running { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    fn spawn(&self, cmd: String, opts: ExecOpts, stdin: Option<Vec<u8>>, owner: Option<String>) -> (u64, Arc<Job>) {
+        self.purge();
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u64;
+
+        let job = Arc::new(Job {
+            command: cmd,
+            owner: owner,
+            started: SystemTime::now(),
+            state: Mutex::new(JobState::default()),
+            done: Condvar::new(),
+            cancel: AtomicBool::new(false),
+        });
+        self.jobs.lock().unwrap().insert(id, job.clone());
+
+        let thread_job = job.clone();
+        thread::spawn(move || {
+            let job = thread_job;
+            let result = process::exec_cancellable(&job.command, &opts, stdin, &job.cancel, |stream, chunk| {
+                let mut state = job.state.lock().unwrap();
+                let truncated = match stream {
+                    Stream::Stdout => append(&mut state.stdout, chunk, MAX_OUTPUT),
+                    Stream::Stderr => append(&mut state.stderr, chunk, MAX_OUTPUT),
+                };
+                state.truncated |= truncated;
+                Ok(())
+            });
+
+            let mut state = job.state.lock().unwrap();
+            match result {
+                Ok((exit_code, timed_out)) => {
+                    state.exit_code = Some(exit_code);
+                    state.timed_out = timed_out;
+                },
+                Err(e) => state.error = Some(e.to_string()),
+            }
+            state.finished = Some(SystemTime::now());
+            job.done.notify_all();
+        });
+
+        (id, job)
+    }
+
+    // Other clients' jobs are reported as unknown, so their ids don't
+    // give anything away.
+    fn get(&self, id: &str, user_id: Option<&str>) -> Result<(u64, Arc<Job>)> {
+        self.purge();
+        let id = id.parse()?;
+        match self.jobs.lock().unwrap().get(&id) {
+            Some(job) if job.owner.as_ref().map(|o| o.as_str()) == user_id => Ok((id, job.clone())),
+            _ => Err(Error::InvalidArg(format!("Unknown job: {}", id))),
+        }
+    }
+
+    // Forget finished jobs once they're older than the retention period
+    fn purge(&self) {
+        let retention = self.retention;
+        self.jobs.lock().unwrap().retain(|_, job| {
+            match job.state.lock().unwrap().finished {
+                Some(f) => f.elapsed().map(|e| e < retention).unwrap_or(true),
+                None => true,
+            }
+        });
+    }
+}
+
+fn send_status(sock: &mut ZSock, router_id: &[u8], id: u64, job: &Job) -> Result<()> {
+    let msg = ZMsg::new_ok()?;
+    msg.pushstr("")?;
+    msg.pushbytes(router_id)?;
+    msg.addstr(&serde_json::to_string(&status(id, job))?)?;
+    msg.send(sock)?;
+    Ok(())
+}
+
+fn status(id: u64, job: &Job) -> JobStatus {
+    let state = job.state.lock().unwrap();
+    JobStatus {
+        id: id,
+        command: job.command.clone(),
+        state: if state.finished.is_none() {
+            "running"
+        } else if state.error.is_some() {
+            "failed"
+        } else if state.timed_out {
+            "timed_out"
+        } else if job.cancel.load(Ordering::SeqCst) {
+            "killed"
+        } else {
+            "finished"
+        },
+        exit_code: state.exit_code,
+        error: state.error.clone(),
+        started: timestamp(job.started),
+        finished: state.finished.map(timestamp),
+        truncated: state.truncated,
+    }
+}
+
+// Appends as much of `chunk` as fits within `limit`, returning whether
+// any of it was discarded.
+fn append(buf: &mut Vec<u8>, chunk: &[u8], limit: usize) -> bool {
+    let len = chunk.len().min(limit.saturating_sub(buf.len()));
+    buf.extend_from_slice(&chunk[..len]);
+    len < chunk.len()
+}
+
+fn wait(job: &Job, timeout: Duration) {
+    let start = Instant::now();
+    let mut state = job.state.lock().unwrap();
+    while state.finished.is_none() {
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            break;
+        }
+        state = job.done.wait_timeout(state, timeout - elapsed).unwrap().0;
+    }
+}
+
+// Returns whether the job was still running
+fn cancel(job: &Job) -> bool {
+    let running = job.state.lock().unwrap().finished.is_none();
+    if running {
+        job.cancel.store(true, Ordering::SeqCst);
+    }
+    running
+}
+
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{CommandPolicy, Limits};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use super::{append, cancel, status, wait, JobApi, Policy};
+    use super::super::process::ExecOpts;
+
+    fn job_api(retention: u64) -> JobApi {
+        JobApi::new(retention, Limits::default(), Arc::new(Policy::new(&CommandPolicy::default()).unwrap()))
+    }
+
+    #[test]
+    fn test_wait_status() {
+        let api = job_api(3600);
+        let (id, job) = api.spawn("echo hi".into(), ExecOpts::default(), None, None);
+        wait(&job, Duration::from_secs(10));
+
+        let s = status(id, &job);
+        assert_eq!(s.state, "finished");
+        assert_eq!(s.exit_code, Some(0));
+        assert!(s.finished.is_some());
+        assert_eq!(job.state.lock().unwrap().stdout, b"hi\n");
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let api = job_api(3600);
+        let (id, job) = api.spawn("sleep 30".into(), ExecOpts::default(), None, None);
+        wait(&job, Duration::from_millis(100));
+        assert_eq!(status(id, &job).state, "running");
+        cancel(&job);
+    }
+
+    #[test]
+    fn test_kill() {
+        let api = job_api(3600);
+        let (id, job) = api.spawn("sleep 30".into(), ExecOpts::default(), None, None);
+        assert!(cancel(&job));
+        wait(&job, Duration::from_secs(10));
+        assert_eq!(status(id, &job).state, "killed");
+        assert!(!cancel(&job));
+    }
+
+    #[test]
+    fn test_purge() {
+        let api = job_api(0);
+        let (finished, job) = api.spawn("true".into(), ExecOpts::default(), None, None);
+        wait(&job, Duration::from_secs(10));
+        let (running, job) = api.spawn("sleep 30".into(), ExecOpts::default(), None, None);
+
+        api.purge();
+        assert!(api.get(&finished.to_string(), None).is_err());
+        assert!(api.get(&running.to_string(), None).is_ok());
+        cancel(&job);
+    }
+
+    #[test]
+    fn test_owner() {
+        let api = job_api(3600);
+        let (id, job) = api.spawn("sleep 30".into(), ExecOpts::default(), None, Some("alice".into()));
+        assert!(api.get(&id.to_string(), Some("alice")).is_ok());
+        assert!(api.get(&id.to_string(), Some("bob")).is_err());
+        assert!(api.get(&id.to_string(), None).is_err());
+        cancel(&job);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut buf = Vec::new();
+        assert!(!append(&mut buf, b"abc", 5));
+        assert!(append(&mut buf, b"def", 5));
+        assert_eq!(buf, b"abcde");
+        assert!(append(&mut buf, b"g", 5));
+        assert_eq!(buf, b"abcde");
+    }
+}
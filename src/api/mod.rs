@@ -6,42 +6,115 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+mod checksum;
 mod command;
 mod directory;
 mod file;
+mod job;
 mod package;
+mod policy;
+mod process;
+mod server;
 mod service;
+mod stat;
 mod telemetry;
 
+use config::Config;
 use czmq::{ZCert, ZFrame, ZMsg, ZSock, SocketType};
-use error::Result;
+use error::{Error, Result};
 use inapi::Host;
+use libc;
 use self::command::CommandApi;
 use self::directory::DirectoryApi;
 use self::file::FileApi;
+use self::job::JobApi;
 use self::package::PackageApi;
+use self::policy::Policy;
+use self::server::{Server, Worker};
 use self::service::ServiceApi;
 use self::telemetry::TelemetryApi;
 use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::rc::Rc;
 use std::result::Result as StdResult;
-use zdaemon::{Api, Error as DError, ZMsgExtended};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tempfile::NamedTempFile;
+use zdaemon::{Error as DError, ZMsgExtended};
 
-pub fn endpoint(api_port: u32, cert: &ZCert) -> Result<Api> {
+const WORKER_ENDPOINT: &'static str = "inproc://api_workers";
+
+pub fn endpoint(config: Arc<Config>, cert: &ZCert) -> Result<Server> {
     let mut api_sock = ZSock::new(SocketType::ROUTER);
     cert.apply(&mut api_sock);
     api_sock.set_zap_domain("agent.intecture");
     api_sock.set_curve_server(true);
     api_sock.set_linger(1000);
-    api_sock.bind(&format!("tcp://*:{}", api_port))?;
+    api_sock.bind(&format!("tcp://*:{}", config.api_port))?;
+
+    let mut worker_sock = ZSock::new(SocketType::ROUTER);
+    worker_sock.set_linger(0);
+    worker_sock.bind(WORKER_ENDPOINT)?;
+
+    // Jobs must be visible to every worker, as a client's follow-up
+    // requests may be handled by a different thread.
+    let policy = Arc::new(Policy::new(&config.command_policy)?);
+    let job_api = Arc::new(JobApi::new(config.job_retention, config.exec_limits.clone(), policy.clone()));
+
+    // Handlers hold `Rc`s, so each worker has to build its own on its
+    // thread, then report back whether that succeeded.
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..config.api_threads {
+        let config = config.clone();
+        let policy = policy.clone();
+        let job_api = job_api.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            match worker(&config, policy, job_api) {
+                // Don't serve requests if another worker failed
+                Ok(mut api) => if tx.send(None).is_ok() {
+                    api.run();
+                },
+                Err(e) => { let _ = tx.send(Some(e.to_string())); },
+            }
+        });
+    }
+    drop(tx);
+
+    for _ in 0..config.api_threads {
+        if let Some(e) = rx.recv().unwrap_or_else(|_| Some("Worker thread panicked".into())) {
+            return Err(Error::ApiWorker(e));
+        }
+    }
+
+    Ok(Server::new(api_sock, worker_sock))
+}
 
-    let mut api = Api::new(api_sock);
+fn worker(config: &Config, policy: Arc<Policy>, job_api: Arc<JobApi>) -> Result<Worker> {
+    let mut sock = ZSock::new(SocketType::DEALER);
+    sock.set_linger(0);
+    sock.connect(WORKER_ENDPOINT)?;
 
+    let mut api = Worker::new(sock);
+    register(&mut api, config, policy, job_api)?;
+    Ok(api)
+}
+
+// Each worker has its own `Host`, so handlers on different threads
+// never contend for it.
+fn register(api: &mut Worker, config: &Config, policy: Arc<Policy>, job_api: Arc<JobApi>) -> Result<()> {
     let path: Option<String> = None;
     let host = Rc::new(RefCell::new(Host::local(path)?));
 
-    let host_clone = host.clone();
-    api.add("command::exec", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = CommandApi::exec(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let command_api = Rc::new(CommandApi::new(host.clone(), config.exec_limits.clone(), policy));
+    let command_clone = command_api.clone();
+    api.add("command::exec", move |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let u = user_id(&frame); let r = command_clone.exec(sock, u.as_ref().map(|s| s.as_str()), &i); error_handler(sock, r, &i) });
+    let command_clone = command_api.clone();
+    api.add("command::exec_argv", move |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let u = user_id(&frame); let r = command_clone.exec_argv(sock, u.as_ref().map(|s| s.as_str()), &i); error_handler(sock, r, &i) });
 
     let directory_api = Rc::new(DirectoryApi::new(host.clone()));
     let directory_clone = directory_api.clone();
@@ -62,6 +135,12 @@ pub fn endpoint(api_port: u32, cert: &ZCert) -> Result<Api> {
     api.add("directory::get_mode", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = directory_clone.get_mode(sock, &i); error_handler(sock, r, &i) });
     let directory_clone = directory_api.clone();
     api.add("directory::set_mode", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = directory_clone.set_mode(sock, &i); error_handler(sock, r, &i) });
+    let directory_clone = directory_api.clone();
+    api.add("directory::checksum_tree", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = directory_clone.checksum_tree(sock, &i); error_handler(sock, r, &i) });
+    let directory_clone = directory_api.clone();
+    api.add("directory::stat", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = directory_clone.stat(sock, &i); error_handler(sock, r, &i) });
+    let directory_clone = directory_api.clone();
+    api.add("directory::list", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = directory_clone.list(sock, &i); error_handler(sock, r, &i) });
 
     let file_api = Rc::new(FileApi::new(host.clone())?);
     let file_clone = file_api.clone();
@@ -82,17 +161,138 @@ pub fn endpoint(api_port: u32, cert: &ZCert) -> Result<Api> {
     api.add("file::get_mode", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = file_clone.get_mode(sock, &i); error_handler(sock, r, &i) });
     let file_clone = file_api.clone();
     api.add("file::set_mode", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = file_clone.set_mode(sock, &i); error_handler(sock, r, &i) });
+    let file_clone = file_api.clone();
+    api.add("file::read", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = file_clone.read(sock, &i); error_handler(sock, r, &i) });
+    let file_clone = file_api.clone();
+    api.add("file::write", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = file_clone.write(sock, &i); error_handler(sock, r, &i) });
+    let file_clone = file_api.clone();
+    api.add("file::checksum", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = file_clone.checksum(sock, &i); error_handler(sock, r, &i) });
+    let file_clone = file_api.clone();
+    api.add("file::stat", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = file_clone.stat(sock, &i); error_handler(sock, r, &i) });
+
+    let job_clone = job_api.clone();
+    api.add("job::start", move |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let u = user_id(&frame); let r = job_clone.start(sock, u.as_ref().map(|s| s.as_str()), &i); error_handler(sock, r, &i) });
+    let job_clone = job_api.clone();
+    api.add("job::status", move |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let u = user_id(&frame); let r = job_clone.status(sock, u.as_ref().map(|s| s.as_str()), &i); error_handler(sock, r, &i) });
+    let job_clone = job_api.clone();
+    api.add("job::output", move |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let u = user_id(&frame); let r = job_clone.output(sock, u.as_ref().map(|s| s.as_str()), &i); error_handler(sock, r, &i) });
+    let job_clone = job_api.clone();
+    api.add("job::wait", move |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let u = user_id(&frame); let r = job_clone.wait(sock, u.as_ref().map(|s| s.as_str()), &i); error_handler(sock, r, &i) });
+    let job_clone = job_api.clone();
+    api.add("job::kill", move |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let u = user_id(&frame); let r = job_clone.kill(sock, u.as_ref().map(|s| s.as_str()), &i); error_handler(sock, r, &i) });
 
     let host_clone = host.clone();
     api.add("package::default_provider", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::default_provider(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::install", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::install(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::install_many", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::install_many(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::list_installed", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::list_installed(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::repo_add", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::repo_add(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::repo_remove", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::repo_remove(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::repo_list", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::repo_list(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::install_version", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::install_version(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::hold", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::hold(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::unhold", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::unhold(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::held", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::held(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::update_cache", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::update_cache(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::upgrade", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::upgrade(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::uninstall", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::uninstall(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("package::is_installed", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = PackageApi::is_installed(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
 
     let host_clone = host.clone();
     api.add("service::action", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::action(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("service::status", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::status(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("service::list", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::list(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("service::install_unit", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::install_unit(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("service::remove_unit", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::remove_unit(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("service::enable", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::enable(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("service::disable", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::disable(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
+    let host_clone = host.clone();
+    api.add("service::is_enabled", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = ServiceApi::is_enabled(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
 
     let host_clone = host.clone();
     api.add("telemetry", move |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = TelemetryApi::get(sock, &mut host_clone.borrow_mut(), &i); error_handler(sock, r, &i) });
 
-    Ok(api)
+    Ok(())
+}
+
+/// The ZAP user id of the client, which identifies its certificate.
+/// The server forwards it in `frame`, as metadata doesn't survive the
+/// hop to the workers.
+fn user_id(frame: &ZFrame) -> Option<String> {
+    match frame.data() {
+        Ok(Ok(ref u)) if !u.is_empty() => Some(u.clone()),
+        _ => None,
+    }
+}
+
+/// Wraps an argument in single quotes so it reaches the shell as a
+/// single literal word.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace("'", "'\\''"))
+}
+
+/// Checks that a name can be used as a file name without escaping the
+/// directory it is joined to.
+fn check_name(name: &str) -> Result<&str> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        Err(Error::InvalidArg(format!("Invalid name: {}", name)))
+    } else {
+        Ok(name)
+    }
+}
+
+/// Atomically replaces the file at `path` if its contents differ,
+/// returning whether anything was written.
+fn write_if_changed(path: &Path, contents: &[u8], mode: Option<u32>) -> Result<bool> {
+    write_if_changed_as(path, contents, mode, None)
+}
+
+/// Like `write_if_changed`, also setting the file's owner. The mode and
+/// owner are applied before the file is moved into place.
+fn write_if_changed_as(path: &Path, contents: &[u8], mode: Option<u32>, owner: Option<(libc::uid_t, libc::gid_t)>) -> Result<bool> {
+    if path.is_file() {
+        let mut existing = Vec::new();
+        fs::File::open(path)?.read_to_end(&mut existing)?;
+        if existing == contents {
+            return Ok(false);
+        }
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    // chown may clear setuid bits, so it has to come first
+    if let Some((uid, gid)) = owner {
+        if unsafe { libc::fchown(tmp.as_raw_fd(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    if let Some(m) = mode {
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(m))?;
+    }
+    tmp.persist(path)?;
+    Ok(true)
 }
 
 fn error_handler(sock: &mut ZSock, result: Result<()>, router_id: &[u8]) -> StdResult<(), DError> {
@@ -114,7 +314,8 @@ mod tests {
     use czmq::{ZMsg, ZSock};
     use error::Error;
     use std::error::Error as StdError;
-    use super::error_handler;
+    use super::{check_name, error_handler, shell_quote, write_if_changed};
+    use tempdir::TempDir;
 
     #[test]
     fn test_error_handler() {
@@ -132,4 +333,28 @@ mod tests {
         assert_eq!(msg.popstr().unwrap().unwrap(), "Err");
         assert_eq!(msg.popstr().unwrap().unwrap(), e_desc);
     }
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("nginx").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("..").is_err());
+        assert!(check_name("../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_write_if_changed() {
+        let tmpdir = TempDir::new("test_write_if_changed").unwrap();
+        let path = tmpdir.path().join("nginx.list");
+        assert!(write_if_changed(&path, b"deb http://nginx.org/packages/debian/ jessie nginx", None).unwrap());
+        assert!(!write_if_changed(&path, b"deb http://nginx.org/packages/debian/ jessie nginx", None).unwrap());
+        assert!(write_if_changed(&path, b"deb http://nginx.org/packages/debian/ stretch nginx", Some(0o600)).unwrap());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("abc"), "'abc'");
+        assert_eq!(shell_quote("a b;c"), "'a b;c'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
 }
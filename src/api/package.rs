@@ -7,10 +7,122 @@
 // modified, or distributed except according to those terms.
 
 use czmq::{ZMsg, ZSock};
-use error::Result;
-use inapi::{ProviderFactory, Host};
+use error::{Error, Result};
+use glob::Pattern;
+use inapi::{Command, CommandResult, Host, Package, ProviderFactory, Providers};
+use serde_json;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use super::{check_name, shell_quote, write_if_changed};
 use zdaemon::ZMsgExtended;
 
+#[derive(Debug, Default, Serialize)]
+struct BatchResult {
+    present: Vec<String>,
+    installed: Vec<String>,
+    failed: Vec<String>,
+    exit_code: Option<i32>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Repository {
+    name: String,
+    definition: String,
+    key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Upgrade {
+    name: String,
+    current: Option<String>,
+    available: String,
+}
+
+// Where a provider keeps its repository definitions and signing keys
+struct RepoLayout {
+    dir: &'static str,
+    ext: &'static str,
+    key_dir: &'static str,
+    key_ext: &'static str,
+    key_import: Option<&'static str>,
+    // Lists and removes imported keys, so `repo_remove` can undo an
+    // import
+    key_query: Option<&'static str>,
+    key_erase: Option<&'static str>,
+}
+
+impl RepoLayout {
+    fn new(provider: &Providers) -> Result<RepoLayout> {
+        match *provider {
+            // Keys are stored ASCII armored, which apt reads from
+            // trusted.gpg.d since 1.4. See `check_apt_version`.
+            Providers::Apt => Ok(RepoLayout {
+                dir: "/etc/apt/sources.list.d",
+                ext: "list",
+                key_dir: "/etc/apt/trusted.gpg.d",
+                key_ext: "asc",
+                key_import: None,
+                key_query: None,
+                key_erase: None,
+            }),
+            Providers::Dnf | Providers::Yum => Ok(RepoLayout {
+                dir: "/etc/yum.repos.d",
+                ext: "repo",
+                key_dir: "/etc/pki/rpm-gpg",
+                key_ext: "key",
+                key_import: Some("rpm --import"),
+                key_query: Some("rpm -q gpg-pubkey"),
+                key_erase: Some("rpm -e --allmatches"),
+            }),
+            Providers::Pkg => Ok(RepoLayout {
+                dir: "/usr/local/etc/pkg/repos",
+                ext: "conf",
+                key_dir: "/usr/local/etc/pkg/keys",
+                key_ext: "pub",
+                key_import: None,
+                key_query: None,
+                key_erase: None,
+            }),
+            _ => Err(Error::Unsupported(format!("{} does not support repository management", provider))),
+        }
+    }
+
+    fn definition_path(&self, name: &str) -> Result<PathBuf> {
+        Ok(Path::new(self.dir).join(format!("{}.{}", check_name(name)?, self.ext)))
+    }
+
+    fn key_path(&self, name: &str) -> Result<PathBuf> {
+        Ok(Path::new(self.key_dir).join(format!("{}.{}", check_name(name)?, self.key_ext)))
+    }
+
+    // Records which keys importing the repository's key file added
+    fn imported_path(&self, name: &str) -> Result<PathBuf> {
+        Ok(Path::new(self.key_dir).join(format!("{}.{}.imported", check_name(name)?, self.key_ext)))
+    }
+
+    fn imported_keys(&self, host: &mut Host) -> Result<Vec<String>> {
+        match self.key_query {
+            // rpm exits non-zero when no keys are installed
+            Some(query) => Ok(Command::new(query).exec(host)?.stdout.lines()
+                                                 .filter(|l| l.starts_with("gpg-pubkey-"))
+                                                 .map(|l| l.trim().to_string())
+                                                 .collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct InstalledPackage {
+    name: String,
+    version: String,
+    architecture: Option<String>,
+    provider: String,
+}
+
 pub struct PackageApi;
 
 impl PackageApi {
@@ -23,4 +135,660 @@ impl PackageApi {
         msg.send(sock)?;
         Ok(())
     }
+
+    pub fn install(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let mut package = recv_package(sock, host)?;
+        let result = package.install(host)?;
+        send_result(sock, router_id, result)
+    }
+
+    pub fn uninstall(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let mut package = recv_package(sock, host)?;
+        let result = package.uninstall(host)?;
+        send_result(sock, router_id, result)
+    }
+
+    pub fn install_many(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 2, None, false)?;
+        let provider = parse_provider(&request.popstr().unwrap().or(Err(Error::MessageUtf8))?)?;
+        let mut names = Vec::new();
+        while let Some(name) = request.popstr() {
+            names.push(name.or(Err(Error::MessageUtf8))?);
+        }
+
+        let provider = ProviderFactory::create(host, provider)?;
+        let mut batch = BatchResult::default();
+        let mut pending = Vec::new();
+        for name in names {
+            if provider.is_installed(host, &name)? {
+                batch.present.push(name);
+            } else {
+                pending.push(name);
+            }
+        }
+
+        if !pending.is_empty() {
+            let cmd = install_many_cmd(&provider.get_providers(), &pending)?;
+            let result = Command::new(&cmd).exec(host)?;
+            for name in pending {
+                if provider.is_installed(host, &name)? {
+                    batch.installed.push(name);
+                } else {
+                    batch.failed.push(name);
+                }
+            }
+            batch.exit_code = Some(result.exit_code);
+            batch.stdout = Some(result.stdout);
+            batch.stderr = Some(result.stderr);
+        }
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&batch)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn list_installed(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 0, Some(1), false)?;
+        let pattern = match request.popstr() {
+            Some(f) => {
+                let f = f.or(Err(Error::MessageUtf8))?;
+                if f.is_empty() { None } else { Some(Pattern::new(&f)?) }
+            },
+            None => None,
+        };
+
+        let provider = ProviderFactory::create(host, None)?.get_providers();
+        let result = Command::new(&list_installed_cmd(&provider)?).exec(host)?;
+        if result.exit_code != 0 {
+            return Err(Error::CommandFailed(result.stderr));
+        }
+
+        let packages: Vec<_> = parse_installed(&result.stdout, &provider.to_string())
+            .into_iter()
+            .filter(|p| pattern.as_ref().map_or(true, |pat| pat.matches(&p.name)))
+            .collect();
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&packages)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn repo_add(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 2, Some(3), false)?;
+        let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let definition = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let key = match request.popstr() {
+            Some(k) => k.or(Err(Error::MessageUtf8))?,
+            None => String::new(),
+        };
+
+        let provider = ProviderFactory::create(host, None)?.get_providers();
+        let layout = RepoLayout::new(&provider)?;
+        if let (Providers::Apt, false) = (provider, key.is_empty()) {
+            check_apt_version(host)?;
+        }
+        let mut changed = write_if_changed(&layout.definition_path(&name)?, definition.as_bytes(), None)?;
+        if !key.is_empty() {
+            let key_path = layout.key_path(&name)?;
+            if write_if_changed(&key_path, key.as_bytes(), None)? {
+                changed = true;
+                if let Some(import) = layout.key_import {
+                    let before = layout.imported_keys(host)?;
+                    let result = Command::new(&format!("{} {}", import, shell_quote(&key_path.to_string_lossy()))).exec(host)?;
+                    if result.exit_code != 0 {
+                        // Otherwise a retry would find the key unchanged
+                        // and skip the import
+                        fs::remove_file(&key_path)?;
+                        return Err(Error::CommandFailed(result.stderr));
+                    }
+
+                    // Keys that were already trusted may belong to other
+                    // repositories, so only track the ones we added.
+                    let imported_path = layout.imported_path(&name)?;
+                    let mut imported: Vec<String> = if imported_path.is_file() {
+                        read_file(&imported_path)?.lines().map(|l| l.to_string()).collect()
+                    } else {
+                        Vec::new()
+                    };
+                    for key in layout.imported_keys(host)? {
+                        if !before.contains(&key) && !imported.contains(&key) {
+                            imported.push(key);
+                        }
+                    }
+                    if !imported.is_empty() {
+                        write_if_changed(&imported_path, imported.join("\n").as_bytes(), None)?;
+                    }
+                }
+            }
+        }
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if changed { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn repo_remove(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+
+        let layout = RepoLayout::new(&ProviderFactory::create(host, None)?.get_providers())?;
+        let mut changed = false;
+
+        // Untrust any keys that adding the repository imported
+        let imported_path = layout.imported_path(&name)?;
+        if let (Some(erase), true) = (layout.key_erase, imported_path.is_file()) {
+            let installed = layout.imported_keys(host)?;
+            let keys: Vec<_> = read_file(&imported_path)?.lines()
+                                                         .filter(|k| installed.iter().any(|i| i == k))
+                                                         .map(|k| k.to_string())
+                                                         .collect();
+            if !keys.is_empty() {
+                let result = Command::new(&join_cmd(erase, &keys)).exec(host)?;
+                if result.exit_code != 0 {
+                    return Err(Error::CommandFailed(result.stderr));
+                }
+            }
+        }
+
+        for path in &[layout.definition_path(&name)?, layout.key_path(&name)?, imported_path] {
+            if path.exists() {
+                fs::remove_file(path)?;
+                changed = true;
+            }
+        }
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if changed { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn repo_list(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let layout = RepoLayout::new(&ProviderFactory::create(host, None)?.get_providers())?;
+        let mut repos = Vec::new();
+        if Path::new(layout.dir).is_dir() {
+            for entry in fs::read_dir(layout.dir)? {
+                let path = entry?.path();
+                if path.extension().map_or(true, |e| e != layout.ext) {
+                    continue;
+                }
+                let name = match path.file_stem().and_then(|s| s.to_str()) {
+                    Some(n) => n.to_string(),
+                    None => continue,
+                };
+                let key_path = layout.key_path(&name)?;
+                repos.push(Repository {
+                    definition: read_file(&path)?,
+                    key: if key_path.is_file() { Some(read_file(&key_path)?) } else { None },
+                    name: name,
+                });
+            }
+        }
+        repos.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&repos)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn install_version(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 2, Some(3), false)?;
+        let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let version = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let provider = match request.popstr() {
+            Some(p) => parse_provider(&p.or(Err(Error::MessageUtf8))?)?,
+            None => None,
+        };
+
+        let provider = ProviderFactory::create(host, provider)?.get_providers();
+        let cmd = install_many_cmd(&provider, &[versioned_name(&provider, &name, &version)?])?;
+        let result = Command::new(&cmd).exec(host)?;
+        send_result(sock, router_id, Some(result))
+    }
+
+    pub fn hold(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        set_hold(sock, host, router_id, true)
+    }
+
+    pub fn unhold(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        set_hold(sock, host, router_id, false)
+    }
+
+    pub fn held(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let provider = ProviderFactory::create(host, None)?.get_providers();
+        let held = list_held(host, &provider)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&held)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn update_cache(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let provider = ProviderFactory::create(host, None)?.get_providers();
+        let result = Command::new(update_cache_cmd(&provider)?).exec(host)?;
+        send_result(sock, router_id, Some(result))
+    }
+
+    pub fn upgrade(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, None, false)?;
+        let list_only = request.popstr().unwrap().or(Err(Error::MessageUtf8))? == "1";
+        let mut names = Vec::new();
+        while let Some(name) = request.popstr() {
+            names.push(name.or(Err(Error::MessageUtf8))?);
+        }
+
+        let provider = ProviderFactory::create(host, None)?.get_providers();
+        if list_only {
+            let (cmd, ok_codes) = list_upgrades_cmd(&provider)?;
+            let result = Command::new(cmd).exec(host)?;
+            if !ok_codes.contains(&result.exit_code) {
+                return Err(Error::CommandFailed(result.stderr));
+            }
+            let upgrades: Vec<_> = parse_upgrades(&provider, &result.stdout)
+                .into_iter()
+                .filter(|u| names.is_empty() || names.contains(&u.name))
+                .collect();
+
+            let msg = ZMsg::new_ok()?;
+            msg.pushstr("")?;
+            msg.pushbytes(router_id)?;
+            msg.addstr(&serde_json::to_string(&upgrades)?)?;
+            msg.send(sock)?;
+            Ok(())
+        } else {
+            let result = Command::new(&upgrade_cmd(&provider, &names)?).exec(host)?;
+            send_result(sock, router_id, Some(result))
+        }
+    }
+
+    pub fn is_installed(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let package = recv_package(sock, host)?;
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if package.is_installed() { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+}
+
+// Requests take the package name, optionally followed by a provider
+// override. An empty override frame is the same as omitting it.
+fn recv_package(sock: &mut ZSock, host: &mut Host) -> Result<Package> {
+    let request = ZMsg::expect_recv(sock, 1, Some(2), false)?;
+    let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+    let provider = match request.popstr() {
+        Some(p) => parse_provider(&p.or(Err(Error::MessageUtf8))?)?,
+        None => None,
+    };
+    Ok(Package::new(host, &name, provider)?)
+}
+
+fn set_hold(sock: &mut ZSock, host: &mut Host, router_id: &[u8], hold: bool) -> Result<()> {
+    let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+    let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+
+    let provider = ProviderFactory::create(host, None)?.get_providers();
+    let changed = list_held(host, &provider)?.contains(&name) != hold;
+    if changed {
+        let result = Command::new(&join_cmd(hold_cmd(&provider, hold)?, &[name])).exec(host)?;
+        if result.exit_code != 0 {
+            return Err(Error::CommandFailed(result.stderr));
+        }
+    }
+
+    let msg = ZMsg::new_ok()?;
+    msg.pushstr("")?;
+    msg.pushbytes(router_id)?;
+    msg.addstr(if changed { "1" } else { "0" })?;
+    msg.send(sock)?;
+    Ok(())
+}
+
+fn list_held(host: &mut Host, provider: &Providers) -> Result<Vec<String>> {
+    let result = Command::new(held_cmd(provider)?).exec(host)?;
+    if result.exit_code != 0 {
+        return Err(Error::CommandFailed(result.stderr));
+    }
+    Ok(parse_held(provider, &result.stdout))
+}
+
+fn send_result(sock: &mut ZSock, router_id: &[u8], result: Option<CommandResult>) -> Result<()> {
+    let msg = ZMsg::new_ok()?;
+    msg.pushstr("")?;
+    msg.pushbytes(router_id)?;
+    if let Some(r) = result {
+        msg.send_multi(sock, &[
+            &r.exit_code.to_string(),
+            &r.stdout,
+            &r.stderr,
+        ])?;
+    } else {
+        msg.send(sock)?;
+    }
+    Ok(())
+}
+
+fn install_many_cmd(provider: &Providers, names: &[String]) -> Result<String> {
+    let prefix = match *provider {
+        Providers::Apt => "DEBIAN_FRONTEND=noninteractive apt-get -y install",
+        Providers::Dnf => "dnf -y install",
+        Providers::Homebrew => "brew install",
+        Providers::Macports => "port install",
+        Providers::Nix => "nix-env --install",
+        Providers::Pkg => "pkg install -y",
+        Providers::Yum => "yum -y install",
+        Providers::Ports => return Err(Error::Unsupported(format!("{} cannot install packages in batch", provider))),
+    };
+    Ok(join_cmd(prefix, names))
+}
+
+// Each command prints one package per line as tab separated name,
+// version and architecture. Architecture may be empty.
+fn list_installed_cmd(provider: &Providers) -> Result<&'static str> {
+    match *provider {
+        Providers::Apt => Ok("dpkg-query -W -f='${db:Status-Abbrev}\\t${Package}\\t${Version}\\t${Architecture}\\n' | awk -F'\\t' '$1 ~ /^ii/ { print $2 \"\\t\" $3 \"\\t\" $4 }'"),
+        Providers::Dnf | Providers::Yum => Ok("rpm -qa --qf '%{NAME}\\t%{VERSION}-%{RELEASE}\\t%{ARCH}\\n'"),
+        Providers::Homebrew => Ok("brew list --versions | awk '{ print $1 \"\\t\" $NF \"\\t\" }'"),
+        Providers::Macports => Ok("port -q installed | awk '{ sub(\"^@\", \"\", $2); print $1 \"\\t\" $2 \"\\t\" }'"),
+        Providers::Pkg | Providers::Ports => Ok("pkg query '%n\\t%v\\t%q'"),
+        Providers::Nix => Err(Error::Unsupported(format!("{} cannot list installed packages", provider))),
+    }
+}
+
+fn parse_installed(output: &str, provider: &str) -> Vec<InstalledPackage> {
+    output.lines().filter_map(|line| {
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next()) {
+            (Some(name), Some(version)) if !name.is_empty() => Some(InstalledPackage {
+                name: name.into(),
+                version: version.into(),
+                architecture: fields.next().and_then(|a| if a.is_empty() { None } else { Some(a.into()) }),
+                provider: provider.into(),
+            }),
+            _ => None,
+        }
+    }).collect()
+}
+
+fn update_cache_cmd(provider: &Providers) -> Result<&'static str> {
+    match *provider {
+        Providers::Apt => Ok("apt-get update"),
+        Providers::Dnf => Ok("dnf -y makecache"),
+        Providers::Homebrew => Ok("brew update"),
+        Providers::Macports => Ok("port sync"),
+        Providers::Nix => Ok("nix-channel --update"),
+        Providers::Pkg => Ok("pkg update"),
+        Providers::Yum => Ok("yum -y makecache"),
+        Providers::Ports => Err(Error::Unsupported(format!("{} cannot refresh its package cache", provider))),
+    }
+}
+
+fn upgrade_cmd(provider: &Providers, names: &[String]) -> Result<String> {
+    let (all, some) = match *provider {
+        Providers::Apt => ("DEBIAN_FRONTEND=noninteractive apt-get -y upgrade", "DEBIAN_FRONTEND=noninteractive apt-get -y install --only-upgrade"),
+        Providers::Dnf => ("dnf -y upgrade", "dnf -y upgrade"),
+        Providers::Homebrew => ("brew upgrade", "brew upgrade"),
+        Providers::Macports => ("port upgrade outdated", "port upgrade"),
+        Providers::Pkg => ("pkg upgrade -y", "pkg upgrade -y"),
+        Providers::Yum => ("yum -y update", "yum -y update"),
+        _ => return Err(Error::Unsupported(format!("{} cannot upgrade packages", provider))),
+    };
+    Ok(if names.is_empty() { all.to_string() } else { join_cmd(some, names) })
+}
+
+// Returns the command along with the exit codes that mean success.
+// `yum check-update` exits with 100 when upgrades are available.
+fn list_upgrades_cmd(provider: &Providers) -> Result<(&'static str, Vec<i32>)> {
+    match *provider {
+        Providers::Apt => Ok(("apt-get -s upgrade", vec![0])),
+        Providers::Dnf => Ok(("dnf -q check-update", vec![0, 100])),
+        Providers::Homebrew => Ok(("brew outdated --verbose", vec![0, 1])),
+        Providers::Pkg => Ok(("pkg upgrade -n", vec![0, 1])),
+        Providers::Yum => Ok(("yum -q check-update", vec![0, 100])),
+        _ => Err(Error::Unsupported(format!("{} cannot list available upgrades", provider))),
+    }
+}
+
+fn parse_upgrades(provider: &Providers, output: &str) -> Vec<Upgrade> {
+    output.lines().filter_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match *provider {
+            // Inst nginx [1.10.2-1] (1.10.3-1 Debian:9.0/stable [amd64])
+            Providers::Apt if fields.len() >= 4 && fields[0] == "Inst" => {
+                let (current, available) = if fields[2].starts_with('[') {
+                    (Some(fields[2].trim_matches(|c| c == '[' || c == ']').to_string()), fields[3])
+                } else {
+                    (None, fields[2])
+                };
+                Some(Upgrade {
+                    name: fields[1].into(),
+                    current: current,
+                    available: available.trim_left_matches('(').into(),
+                })
+            },
+            // nginx.x86_64    1:1.12.1-1.el7    epel
+            Providers::Dnf | Providers::Yum if fields.len() == 3 && fields[0].contains('.') => Some(Upgrade {
+                name: fields[0].rsplitn(2, '.').last().unwrap().into(),
+                current: None,
+                available: fields[1].into(),
+            }),
+            // nginx (1.12.0) < 1.12.1
+            Providers::Homebrew if fields.len() >= 4 && fields[2] == "<" => Some(Upgrade {
+                name: fields[0].into(),
+                current: Some(fields[1].trim_matches(|c| c == '(' || c == ')').into()),
+                available: fields[3].into(),
+            }),
+            // nginx: 1.12.0 -> 1.12.1
+            Providers::Pkg if fields.len() == 4 && fields[2] == "->" => Some(Upgrade {
+                name: fields[0].trim_right_matches(':').into(),
+                current: Some(fields[1].into()),
+                available: fields[3].into(),
+            }),
+            _ => None,
+        }
+    }).collect()
+}
+
+fn versioned_name(provider: &Providers, name: &str, version: &str) -> Result<String> {
+    match *provider {
+        Providers::Apt => Ok(format!("{}={}", name, version)),
+        Providers::Dnf | Providers::Pkg | Providers::Yum => Ok(format!("{}-{}", name, version)),
+        // Homebrew's name@version selects a separate versioned formula
+        // rather than pinning an exact version, so isn't supported.
+        _ => Err(Error::Unsupported(format!("{} cannot install a specific package version", provider))),
+    }
+}
+
+fn hold_cmd(provider: &Providers, hold: bool) -> Result<&'static str> {
+    match (provider, hold) {
+        (&Providers::Apt, true) => Ok("apt-mark hold"),
+        (&Providers::Apt, false) => Ok("apt-mark unhold"),
+        (&Providers::Dnf, true) => Ok("dnf -y versionlock add"),
+        (&Providers::Dnf, false) => Ok("dnf -y versionlock delete"),
+        (&Providers::Homebrew, true) => Ok("brew pin"),
+        (&Providers::Homebrew, false) => Ok("brew unpin"),
+        (&Providers::Pkg, true) => Ok("pkg lock -y"),
+        (&Providers::Pkg, false) => Ok("pkg unlock -y"),
+        (&Providers::Yum, true) => Ok("yum -y versionlock add"),
+        (&Providers::Yum, false) => Ok("yum -y versionlock delete"),
+        _ => Err(Error::Unsupported(format!("{} cannot hold packages", provider))),
+    }
+}
+
+fn held_cmd(provider: &Providers) -> Result<&'static str> {
+    match *provider {
+        Providers::Apt => Ok("apt-mark showhold"),
+        Providers::Dnf => Ok("dnf -q versionlock list"),
+        Providers::Homebrew => Ok("brew list --pinned"),
+        Providers::Pkg => Ok("pkg query -e '%k = 1' '%n'"),
+        Providers::Yum => Ok("yum -q versionlock list"),
+        _ => Err(Error::Unsupported(format!("{} cannot hold packages", provider))),
+    }
+}
+
+// Versionlock entries look like "0:nginx-1.10.2-1.el7.*" (yum) or
+// "nginx-1:1.10.2-1.el7.*" (dnf), so strip them back to the name.
+fn parse_held(provider: &Providers, output: &str) -> Vec<String> {
+    let lines = output.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+    match *provider {
+        Providers::Dnf | Providers::Yum => lines.filter(|l| l.ends_with(".*"))
+            .filter_map(|l| l.rsplitn(3, '-').last())
+            .map(|n| n.rsplit(':').next().unwrap().to_string())
+            .collect(),
+        _ => lines.map(|l| l.to_string()).collect(),
+    }
+}
+
+// Older versions of apt ignore .asc files in trusted.gpg.d, so the key
+// would silently go unused.
+fn check_apt_version(host: &mut Host) -> Result<()> {
+    let result = Command::new("apt-get --version").exec(host)?;
+    if result.exit_code != 0 {
+        return Err(Error::CommandFailed(result.stderr));
+    }
+    if apt_reads_asc(&result.stdout) {
+        Ok(())
+    } else {
+        Err(Error::Unsupported("Repository keys require apt 1.4 or later".into()))
+    }
+}
+
+// The first line of `apt-get --version`, e.g. "apt 1.4.8 (amd64)"
+fn apt_reads_asc(version: &str) -> bool {
+    let parts: Vec<u32> = version.split_whitespace()
+                                 .nth(1)
+                                 .unwrap_or("")
+                                 .split('.')
+                                 .take(2)
+                                 .filter_map(|n| n.parse().ok())
+                                 .collect();
+    parts.len() == 2 && (parts[0], parts[1]) >= (1, 4)
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    let mut fh = fs::File::open(path)?;
+    let mut contents = String::new();
+    fh.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn join_cmd(prefix: &str, args: &[String]) -> String {
+    let mut cmd = prefix.to_string();
+    for arg in args {
+        cmd.push(' ');
+        cmd.push_str(&shell_quote(arg));
+    }
+    cmd
+}
+
+fn parse_provider(name: &str) -> Result<Option<Providers>> {
+    match name.to_lowercase().as_ref() {
+        "" => Ok(None),
+        "apt" => Ok(Some(Providers::Apt)),
+        "dnf" => Ok(Some(Providers::Dnf)),
+        "homebrew" => Ok(Some(Providers::Homebrew)),
+        "macports" => Ok(Some(Providers::Macports)),
+        "nix" => Ok(Some(Providers::Nix)),
+        "pkg" => Ok(Some(Providers::Pkg)),
+        "ports" => Ok(Some(Providers::Ports)),
+        "yum" => Ok(Some(Providers::Yum)),
+        _ => Err(Error::InvalidProvider(name.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use inapi::Providers;
+    use super::{apt_reads_asc, install_many_cmd, parse_held, parse_installed, parse_provider, parse_upgrades, upgrade_cmd, versioned_name};
+
+    #[test]
+    fn test_parse_provider() {
+        assert!(parse_provider("").unwrap().is_none());
+        assert!(match parse_provider("Apt").unwrap() { Some(Providers::Apt) => true, _ => false });
+        assert!(match parse_provider("yum").unwrap() { Some(Providers::Yum) => true, _ => false });
+        assert!(parse_provider("bogus").is_err());
+    }
+
+    #[test]
+    fn test_apt_reads_asc() {
+        assert!(apt_reads_asc("apt 1.4.8 (amd64)\nSupported modules:\n"));
+        assert!(apt_reads_asc("apt 2.2.4 (amd64)"));
+        assert!(!apt_reads_asc("apt 1.0.9.8.4 for amd64 compiled on Jun 21 2017 09:04:19"));
+        assert!(!apt_reads_asc(""));
+    }
+
+    #[test]
+    fn test_install_many_cmd() {
+        let names = vec!["nginx".to_string(), "it's".to_string()];
+        assert_eq!(install_many_cmd(&Providers::Yum, &names).unwrap(), "yum -y install 'nginx' 'it'\\''s'");
+        assert!(install_many_cmd(&Providers::Ports, &names).is_err());
+    }
+
+    #[test]
+    fn test_parse_installed() {
+        let packages = parse_installed("nginx\t1.10.3-1\tamd64\nbash\t4.4\t\n\n", "Apt");
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].name, "nginx");
+        assert_eq!(packages[0].version, "1.10.3-1");
+        assert_eq!(packages[0].architecture, Some("amd64".to_string()));
+        assert_eq!(packages[0].provider, "Apt");
+        assert!(packages[1].architecture.is_none());
+    }
+
+    #[test]
+    fn test_versioned_name() {
+        assert_eq!(versioned_name(&Providers::Apt, "nginx", "1.10.3").unwrap(), "nginx=1.10.3");
+        assert_eq!(versioned_name(&Providers::Yum, "nginx", "1.10.3").unwrap(), "nginx-1.10.3");
+        assert!(versioned_name(&Providers::Homebrew, "nginx", "1.10.3").is_err());
+    }
+
+    #[test]
+    fn test_parse_held() {
+        assert_eq!(parse_held(&Providers::Apt, "nginx\nlinux-image-amd64\n"), vec!["nginx", "linux-image-amd64"]);
+        assert_eq!(parse_held(&Providers::Yum, "Loaded plugins: versionlock\n0:postgresql-server-9.2.18-1.el7.*\n"), vec!["postgresql-server"]);
+        assert_eq!(parse_held(&Providers::Dnf, "kernel-0:4.11.8-300.fc26.*\n"), vec!["kernel"]);
+    }
+
+    #[test]
+    fn test_upgrade_cmd() {
+        assert_eq!(upgrade_cmd(&Providers::Yum, &[]).unwrap(), "yum -y update");
+        assert_eq!(upgrade_cmd(&Providers::Yum, &["nginx".into()]).unwrap(), "yum -y update 'nginx'");
+        assert!(upgrade_cmd(&Providers::Nix, &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_upgrades() {
+        let apt = parse_upgrades(&Providers::Apt, "Reading package lists...\nInst nginx [1.10.2-1] (1.10.3-1 Debian:9.0/stable [amd64])\nConf nginx (1.10.3-1 Debian:9.0/stable [amd64])\n");
+        assert_eq!(apt.len(), 1);
+        assert_eq!(apt[0].name, "nginx");
+        assert_eq!(apt[0].current, Some("1.10.2-1".to_string()));
+        assert_eq!(apt[0].available, "1.10.3-1");
+
+        let yum = parse_upgrades(&Providers::Yum, "\nnginx.x86_64    1:1.12.1-1.el7    epel\n");
+        assert_eq!(yum.len(), 1);
+        assert_eq!(yum[0].name, "nginx");
+        assert_eq!(yum[0].available, "1:1.12.1-1.el7");
+
+        let pkg = parse_upgrades(&Providers::Pkg, "Installed packages to be UPGRADED:\n\tnginx: 1.12.0 -> 1.12.1\n");
+        assert_eq!(pkg.len(), 1);
+        assert_eq!(pkg[0].name, "nginx");
+    }
 }
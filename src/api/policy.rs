@@ -0,0 +1,162 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use config::{CommandPolicy, PolicyRule, PolicyRules};
+use error::{Error, Result};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// A compiled `CommandPolicy`.
+///
+/// Deny rules always win, and global deny rules apply to every client,
+/// including those with rules of their own. If there are any allow
+/// rules, a command must match one of them to run. A client's own allow
+/// rules replace the global ones, unless it has none.
+pub struct Policy {
+    global: RuleSet,
+    identities: HashMap<String, RuleSet>,
+}
+
+struct RuleSet {
+    allow: Vec<Matcher>,
+    deny: Vec<Matcher>,
+}
+
+enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Policy {
+    pub fn new(config: &CommandPolicy) -> Result<Policy> {
+        let mut identities = HashMap::new();
+        for (id, rules) in &config.identities {
+            identities.insert(id.clone(), RuleSet::new(rules)?);
+        }
+
+        Ok(Policy {
+            global: RuleSet {
+                allow: compile(&config.allow)?,
+                deny: compile(&config.deny)?,
+            },
+            identities: identities,
+        })
+    }
+
+    /// Checks whether the client with the given ZAP user id may run
+    /// `cmd`.
+    pub fn check(&self, identity: Option<&str>, cmd: &str) -> Result<()> {
+        let rules = identity.and_then(|i| self.identities.get(i));
+        let allow = match rules {
+            Some(r) if !r.allow.is_empty() => &r.allow,
+            _ => &self.global.allow,
+        };
+        if self.global.deny.iter().any(|m| m.matches(cmd)) ||
+           rules.map(|r| r.deny.iter().any(|m| m.matches(cmd))).unwrap_or(false) ||
+           (!allow.is_empty() && !allow.iter().any(|m| m.matches(cmd))) {
+            Err(Error::Policy(cmd.into()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl RuleSet {
+    fn new(rules: &PolicyRules) -> Result<RuleSet> {
+        Ok(RuleSet {
+            allow: compile(&rules.allow)?,
+            deny: compile(&rules.deny)?,
+        })
+    }
+}
+
+impl Matcher {
+    fn matches(&self, cmd: &str) -> bool {
+        match *self {
+            Matcher::Exact(ref e) => cmd == e,
+            Matcher::Prefix(ref p) => cmd.starts_with(p.as_str()),
+            Matcher::Regex(ref r) => r.is_match(cmd),
+        }
+    }
+}
+
+fn compile(rules: &[PolicyRule]) -> Result<Vec<Matcher>> {
+    let mut matchers = Vec::new();
+    for rule in rules {
+        matchers.push(match *rule {
+            PolicyRule::Exact(ref e) => Matcher::Exact(e.clone()),
+            PolicyRule::Prefix(ref p) => Matcher::Prefix(p.clone()),
+            PolicyRule::Regex(ref r) => Matcher::Regex(Regex::new(r)?),
+        });
+    }
+    Ok(matchers)
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{CommandPolicy, PolicyRule, PolicyRules};
+    use super::Policy;
+
+    #[test]
+    fn test_unrestricted() {
+        let policy = Policy::new(&CommandPolicy::default()).unwrap();
+        assert!(policy.check(None, "rm -rf /tmp/x").is_ok());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut config = CommandPolicy::default();
+        config.allow.push(PolicyRule::Exact("uptime".into()));
+        config.allow.push(PolicyRule::Prefix("systemctl ".into()));
+        config.deny.push(PolicyRule::Regex("[;&|`$]".into()));
+        config.identities.insert("deployer".into(), PolicyRules {
+            allow: vec![PolicyRule::Regex("^/opt/app/bin/".into())],
+            deny: vec![],
+        });
+        let policy = Policy::new(&config).unwrap();
+
+        assert!(policy.check(None, "uptime").is_ok());
+        assert!(policy.check(None, "uptime -p").is_err());
+        assert!(policy.check(None, "systemctl restart nginx").is_ok());
+        assert!(policy.check(None, "systemctl restart nginx; rm -rf /").is_err());
+        assert!(policy.check(Some("someone"), "uptime").is_ok());
+        assert!(policy.check(Some("deployer"), "/opt/app/bin/migrate").is_ok());
+        assert!(policy.check(Some("deployer"), "uptime").is_err());
+        assert!(policy.check(Some("deployer"), "/opt/app/bin/migrate; rm -rf /").is_err());
+    }
+
+    #[test]
+    fn test_identity_deny() {
+        let mut config = CommandPolicy::default();
+        config.identities.insert("deployer".into(), PolicyRules {
+            allow: vec![],
+            deny: vec![PolicyRule::Prefix("rm ".into())],
+        });
+        let policy = Policy::new(&config).unwrap();
+
+        assert!(policy.check(Some("deployer"), "rm -rf /tmp/x").is_err());
+        assert!(policy.check(Some("deployer"), "uptime").is_ok());
+        assert!(policy.check(None, "rm -rf /tmp/x").is_ok());
+
+        // Without allow rules of its own, the global ones still apply
+        config.allow.push(PolicyRule::Exact("uptime".into()));
+        let policy = Policy::new(&config).unwrap();
+
+        assert!(policy.check(Some("deployer"), "uptime").is_ok());
+        assert!(policy.check(Some("deployer"), "reboot").is_err());
+        assert!(policy.check(Some("deployer"), "rm -rf /tmp/x").is_err());
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        let mut config = CommandPolicy::default();
+        config.deny.push(PolicyRule::Regex("(".into()));
+        assert!(Policy::new(&config).is_err());
+    }
+}
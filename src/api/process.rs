@@ -0,0 +1,547 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Runs commands directly rather than via `inapi::Command`, for the
+//! request options that the Intecture API has no concept of.
+
+use config::Limits;
+use error::{Error, Result};
+use libc;
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io::{self, Read, Write};
+use std::{mem, ptr};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to keep reading output after killing a command.
+const KILL_GRACE_MS: u64 = 500;
+
+/// Optional settings that accompany a `command::exec` request as a
+/// JSON frame.
+#[derive(Debug, Default, Deserialize)]
+pub struct ExecOpts {
+    /// Seconds to wait before killing the command's process group
+    pub timeout: Option<u64>,
+    /// User name or uid to run the command as
+    pub user: Option<String>,
+    /// Group name or gid to run the command as. Defaults to the
+    /// user's primary group.
+    pub group: Option<String>,
+    /// Supplementary groups. Defaults to the user's own groups.
+    pub groups: Option<Vec<String>>,
+    /// Working directory for the command
+    pub cwd: Option<String>,
+    /// Environment variables to set, overriding inherited ones
+    pub env: Option<HashMap<String, String>>,
+    /// Start from an empty environment rather than the agent's
+    pub clear_env: Option<bool>,
+    /// Request id to tag streamed output with. When set, output is
+    /// sent in chunks as it arrives rather than on exit.
+    pub stream: Option<String>,
+    /// Resource limits, applied on top of the agent's configured
+    /// defaults
+    #[serde(default)]
+    pub limits: Limits,
+    /// Return stdout and stderr as raw bytes rather than UTF-8 strings
+    pub binary: Option<bool>,
+    /// Also return both streams interleaved in the order the agent
+    /// read them
+    pub combined: Option<bool>,
+}
+
+// Identity to switch to in the child before exec
+struct Credentials {
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    groups: Option<Vec<libc::gid_t>>,
+}
+
+impl Credentials {
+    fn new(opts: &ExecOpts, command: &mut Command) -> Result<Credentials> {
+        let mut creds = Credentials { uid: None, gid: None, groups: None };
+
+        if let Some(ref u) = opts.user {
+            let user = lookup_user(u)?;
+            command.env("HOME", &user.home)
+                   .env("USER", &user.name)
+                   .env("LOGNAME", &user.name);
+            creds.uid = Some(user.uid);
+            creds.gid = Some(user.gid);
+            creds.groups = Some(user_groups(&user.name, user.gid)?);
+        }
+
+        if let Some(ref g) = opts.group {
+            creds.gid = Some(lookup_group(g)?);
+        }
+
+        if let Some(ref groups) = opts.groups {
+            let mut gids = Vec::new();
+            for g in groups {
+                gids.push(lookup_group(g)?);
+            }
+            creds.groups = Some(gids);
+        }
+
+        Ok(creds)
+    }
+
+    // Runs in the forked child, so everything is resolved beforehand and
+    // only async-signal-safe calls are made here. Groups must be dropped
+    // before the gid, and the gid before the uid, while we still have
+    // privileges.
+    fn apply(&self) -> io::Result<()> {
+        unsafe {
+            if let Some(ref groups) = self.groups {
+                check(libc::setgroups(groups.len() as _, groups.as_ptr()))?;
+            }
+            if let Some(gid) = self.gid {
+                check(libc::setgid(gid))?;
+            }
+            if let Some(uid) = self.uid {
+                check(libc::setuid(uid))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub home: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Output {
+    pub exit_code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub combined: Option<Vec<u8>>,
+    pub timed_out: bool,
+}
+
+pub fn exec(cmd: &str, opts: &ExecOpts, stdin: Option<Vec<u8>>) -> Result<Output> {
+    collect(shell(cmd), opts, stdin)
+}
+
+/// Runs `argv[0]` with the remaining arguments directly, without any
+/// shell interpretation.
+pub fn exec_argv(argv: &[String], opts: &ExecOpts, stdin: Option<Vec<u8>>) -> Result<Output> {
+    if argv.is_empty() {
+        return Err(Error::InvalidArg("Missing program to execute".into()));
+    }
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    collect(command, opts, stdin)
+}
+
+/// Like `exec`, but hands output to `on_chunk` as it arrives. Returns
+/// the exit code and whether the command timed out.
+pub fn exec_stream<F>(cmd: &str, opts: &ExecOpts, stdin: Option<Vec<u8>>, on_chunk: F) -> Result<(i32, bool)>
+    where F: FnMut(Stream, &[u8]) -> Result<()>
+{
+    run(shell(cmd), opts, stdin, None, on_chunk)
+}
+
+/// Like `exec_stream`, but the command's process group is killed as
+/// soon as `cancel` is set.
+pub fn exec_cancellable<F>(cmd: &str, opts: &ExecOpts, stdin: Option<Vec<u8>>, cancel: &AtomicBool, on_chunk: F) -> Result<(i32, bool)>
+    where F: FnMut(Stream, &[u8]) -> Result<()>
+{
+    run(shell(cmd), opts, stdin, Some(cancel), on_chunk)
+}
+
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+fn collect(command: Command, opts: &ExecOpts, stdin: Option<Vec<u8>>) -> Result<Output> {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut combined = if opts.combined == Some(true) { Some(Vec::new()) } else { None };
+    let (exit_code, timed_out) = run(command, opts, stdin, None, |stream, chunk| {
+        match stream {
+            Stream::Stdout => stdout.extend_from_slice(chunk),
+            Stream::Stderr => stderr.extend_from_slice(chunk),
+        }
+        if let Some(ref mut c) = combined {
+            c.extend_from_slice(chunk);
+        }
+        Ok(())
+    })?;
+
+    Ok(Output {
+        exit_code: exit_code,
+        stdout: stdout,
+        stderr: stderr,
+        combined: combined,
+        timed_out: timed_out,
+    })
+}
+
+fn run<F>(mut command: Command, opts: &ExecOpts, stdin: Option<Vec<u8>>, cancel: Option<&AtomicBool>, mut on_chunk: F) -> Result<(i32, bool)>
+    where F: FnMut(Stream, &[u8]) -> Result<()>
+{
+    command.stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+           .stdout(Stdio::piped())
+           .stderr(Stdio::piped());
+
+    if opts.clear_env == Some(true) {
+        command.env_clear();
+    }
+    if let Some(ref cwd) = opts.cwd {
+        command.current_dir(cwd);
+    }
+
+    let creds = Credentials::new(opts, &mut command)?;
+
+    if cfg!(not(target_os = "linux")) && opts.limits.ionice.is_some() {
+        return Err(Error::Unsupported("ionice is only available on Linux".into()));
+    }
+    let limits = opts.limits.clone();
+
+    // Put the command in its own process group so that a timeout
+    // kills any children it has spawned too. Limits are applied before
+    // dropping privileges, as raising priority requires root.
+    command.before_exec(move || {
+        check(unsafe { libc::setpgid(0, 0) })?;
+        apply_limits(&limits)?;
+        creds.apply()
+    });
+
+    // Explicit variables win over those derived from the target user
+    if let Some(ref env) = opts.env {
+        command.envs(env);
+    }
+
+    let mut child = command.spawn()?;
+    if let (Some(mut pipe), Some(input)) = (child.stdin.take(), stdin) {
+        // Write from another thread so a command that produces output
+        // before draining stdin can't deadlock us.
+        thread::spawn(move || { let _ = pipe.write_all(&input); });
+    }
+
+    let (tx, rx) = mpsc::channel();
+    read_pipe(child.stdout.take(), Stream::Stdout, tx.clone());
+    read_pipe(child.stderr.take(), Stream::Stderr, tx);
+
+    let deadline = opts.timeout.map(|t| Instant::now() + Duration::from_secs(t));
+    let mut timed_out = false;
+    let mut killed: Option<Instant> = None;
+
+    // The channel disconnects once both pipes reach EOF
+    loop {
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            Ok((stream, chunk)) => if let Err(e) = on_chunk(stream, &chunk) {
+                kill(&child);
+                let _ = child.wait();
+                return Err(e);
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // Daemons that left the group can hold the pipes open forever,
+        // so only wait a moment for the rest of the output.
+        if let Some(k) = killed {
+            if k.elapsed() >= Duration::from_millis(KILL_GRACE_MS) {
+                break;
+            }
+            continue;
+        }
+
+        if let Some(d) = deadline {
+            timed_out = Instant::now() >= d;
+        }
+        let cancelled = cancel.map_or(false, |c| c.load(Ordering::SeqCst));
+
+        // The leader may have exited already while others in its group
+        // hold the pipes open, so kill the group regardless. It can't
+        // have been reused yet, as the leader isn't reaped until below.
+        if timed_out || cancelled {
+            kill(&child);
+            killed = Some(Instant::now());
+        }
+    }
+
+    let status = child.wait()?;
+
+    // Commands killed by a signal have no exit code, so report the
+    // signal the way a shell would.
+    Ok((status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)), timed_out))
+}
+
+fn kill(child: &Child) {
+    unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL); }
+}
+
+fn apply_limits(limits: &Limits) -> io::Result<()> {
+    let rlimits = [
+        (libc::RLIMIT_CPU, limits.cpu),
+        (libc::RLIMIT_AS, limits.address_space),
+        (libc::RLIMIT_NOFILE, limits.open_files),
+        (libc::RLIMIT_NPROC, limits.processes),
+    ];
+    for &(resource, value) in rlimits.iter() {
+        if let Some(v) = value {
+            let rlim = libc::rlimit { rlim_cur: v as libc::rlim_t, rlim_max: v as libc::rlim_t };
+            check(unsafe { libc::setrlimit(resource, &rlim) })?;
+        }
+    }
+
+    if let Some(nice) = limits.nice {
+        check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+    }
+
+    if let Some(level) = limits.ionice {
+        set_ionice(level)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_ionice(level: u32) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_BE: libc::c_long = 2;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    let ioprio = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | (level.min(7) as libc::c_long);
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ionice(_: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "ionice is only available on Linux"))
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// Looks up a user by name or uid.
+pub fn lookup_user(user: &str) -> Result<User> {
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+
+    let ret = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) },
+        Err(_) => {
+            let name = CString::new(user).or(Err(Error::InvalidArg(format!("Unknown user: {}", user))))?;
+            unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) }
+        }
+    };
+
+    if ret != 0 || result.is_null() {
+        return Err(Error::InvalidArg(format!("Unknown user: {}", user)));
+    }
+
+    unsafe {
+        Ok(User {
+            name: CStr::from_ptr(pwd.pw_name).to_string_lossy().into_owned(),
+            uid: pwd.pw_uid,
+            gid: pwd.pw_gid,
+            home: CStr::from_ptr(pwd.pw_dir).to_string_lossy().into_owned(),
+        })
+    }
+}
+
+// The user's supplementary groups, including `gid`
+fn user_groups(name: &str, gid: libc::gid_t) -> Result<Vec<libc::gid_t>> {
+    let c_name = CString::new(name).or(Err(Error::InvalidArg(format!("Unknown user: {}", name))))?;
+    let mut count: libc::c_int = 32;
+    loop {
+        let mut groups = vec![0 as libc::gid_t; count as usize];
+        let ret = unsafe { libc::getgrouplist(c_name.as_ptr(), gid as _, groups.as_mut_ptr() as _, &mut count) };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // glibc reports the number of groups needed, other platforms
+        // don't, so grow the buffer at least twofold.
+        count = cmp::max(count, groups.len() as libc::c_int * 2);
+        if count > 65536 {
+            return Err(Error::InvalidArg(format!("Too many groups for user: {}", name)));
+        }
+    }
+}
+
+/// Looks up a group's gid by name or gid.
+pub fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+    let name = CString::new(group).or(Err(Error::InvalidArg(format!("Unknown group: {}", group))))?;
+    let ret = unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+
+    if ret != 0 || result.is_null() {
+        Err(Error::InvalidArg(format!("Unknown group: {}", group)))
+    } else {
+        Ok(grp.gr_gid)
+    }
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>, stream: Stream, tx: Sender<(Stream, Vec<u8>)>) {
+    if let Some(mut p) = pipe {
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match p.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => if tx.send((stream, buf[..n].to_vec())).is_err() {
+                        break;
+                    },
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use super::{exec, exec_argv, exec_stream, lookup_group, lookup_user, user_groups, ExecOpts, Stream};
+
+    #[test]
+    fn test_exec() {
+        let output = exec("echo out; echo err >&2; exit 3", &ExecOpts::default(), None).unwrap();
+        assert_eq!(output.exit_code, 3);
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+        assert!(!output.timed_out);
+    }
+
+    #[test]
+    fn test_exec_timeout() {
+        let opts = ExecOpts { timeout: Some(1), ..Default::default() };
+        let output = exec("echo partial; sleep 10", &opts, None).unwrap();
+        assert!(output.timed_out);
+        assert_eq!(output.stdout, b"partial\n");
+    }
+
+    #[test]
+    fn test_exec_timeout_background() {
+        let opts = ExecOpts { timeout: Some(1), ..Default::default() };
+        let start = Instant::now();
+        let output = exec("sleep 30 & echo hi", &opts, None).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(output.timed_out);
+        assert_eq!(output.stdout, b"hi\n");
+
+        // A daemon outside the group keeps the pipes open
+        let start = Instant::now();
+        let output = exec("setsid sleep 30 & sleep 30", &opts, None).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(output.timed_out);
+    }
+
+    #[test]
+    fn test_exec_combined() {
+        let mut opts = ExecOpts::default();
+        opts.combined = Some(true);
+        let output = exec("printf '\\377'; sleep 0.1; echo err >&2; sleep 0.1; echo out", &opts, None).unwrap();
+        assert_eq!(output.stdout, b"\xffout\n");
+        assert_eq!(output.combined.unwrap(), b"\xfferr\nout\n");
+        assert!(exec("true", &ExecOpts::default(), None).unwrap().combined.is_none());
+    }
+
+    #[test]
+    fn test_exec_argv() {
+        let argv = vec!["echo".to_string(), "$HOME; `id`".to_string()];
+        let output = exec_argv(&argv, &ExecOpts::default(), None).unwrap();
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.stdout, b"$HOME; `id`\n");
+        assert!(exec_argv(&[], &ExecOpts::default(), None).is_err());
+    }
+
+    #[test]
+    fn test_exec_stream() {
+        let mut chunks = Vec::new();
+        let (exit_code, timed_out) = exec_stream("echo out; echo err >&2", &ExecOpts::default(), None, |stream, chunk| {
+            chunks.push((stream, chunk.to_vec()));
+            Ok(())
+        }).unwrap();
+        assert_eq!(exit_code, 0);
+        assert!(!timed_out);
+        assert!(chunks.contains(&(Stream::Stdout, b"out\n".to_vec())));
+        assert!(chunks.contains(&(Stream::Stderr, b"err\n".to_vec())));
+    }
+
+    #[test]
+    fn test_exec_env() {
+        let mut env = HashMap::new();
+        env.insert("FOO".to_string(), "bar".to_string());
+        let opts = ExecOpts {
+            cwd: Some("/".into()),
+            env: Some(env),
+            clear_env: Some(true),
+            ..Default::default()
+        };
+        let output = exec("echo $FOO; pwd; echo ${HOME:-unset}; cat", &opts, Some(b"input".to_vec())).unwrap();
+        assert_eq!(output.stdout, b"bar\n/\nunset\ninput");
+    }
+
+    #[test]
+    fn test_exec_limits() {
+        let mut opts = ExecOpts::default();
+        opts.limits.open_files = Some(64);
+        opts.limits.nice = Some(5);
+        let output = exec("ulimit -n; ps -o nice= -p $$", &opts, None).unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap().split_whitespace().collect::<Vec<_>>(), vec!["64", "5"]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let root = lookup_user("root").unwrap();
+        assert_eq!(root.uid, 0);
+        assert_eq!(lookup_user("0").unwrap().name, "root");
+        assert!(lookup_user("no_such_user_inagent").is_err());
+        assert_eq!(lookup_group("0").unwrap(), 0);
+        assert!(lookup_group("no_such_group_inagent").is_err());
+    }
+
+    #[test]
+    fn test_user_groups() {
+        let user = lookup_user(&unsafe { libc::getuid() }.to_string()).unwrap();
+        assert!(user_groups(&user.name, user.gid).unwrap().contains(&user.gid));
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use czmq::{RawInterface, ZFrame, ZMsg, ZSock};
+use error::{Error, Result};
+use std::collections::{HashMap, VecDeque};
+use std::result::Result as StdResult;
+use super::error_handler;
+use zdaemon::{Endpoint, Error as DError};
+
+// Handlers are passed the frame holding the client's ZAP user id, which
+// is empty for anonymous clients.
+type Handler = Box<FnMut(&mut ZSock, ZFrame, Option<Vec<u8>>) -> StdResult<(), DError>>;
+
+/// Relays requests from the public ROUTER socket to idle workers, and
+/// replies back again.
+///
+/// Workers announce that they're idle by sending an empty frame, which
+/// can't be mistaken for a reply as ROUTER identities are never empty.
+/// Requests wait in a queue while every worker is busy. Replies keep the
+/// client's routing id as their first frame, so the ROUTER delivers them
+/// to the right client whichever worker handled the request.
+pub struct Server {
+    frontend: ZSock,
+    backend: ZSock,
+    idle: VecDeque<Vec<u8>>,
+    pending: VecDeque<ZMsg>,
+}
+
+impl Server {
+    pub fn new(frontend: ZSock, backend: ZSock) -> Server {
+        Server {
+            frontend: frontend,
+            backend: backend,
+            idle: VecDeque::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn frontend_recv(&mut self) -> Result<()> {
+        let router_id = ZFrame::recv(&mut self.frontend)?;
+        let delimiter = ZFrame::recv(&mut self.frontend)?;
+        if !delimiter.more() {
+            // Nothing to dispatch, and nowhere to send an error
+            return Ok(());
+        }
+
+        // ZAP metadata doesn't survive the hop to the workers, so the
+        // client's user id is forwarded as a frame after the delimiter.
+        // It's read from a frame that came over the wire, as the ROUTER
+        // makes the routing id frame itself.
+        let user_id = delimiter.meta("User-Id").and_then(|u| u.ok()).unwrap_or_default();
+        let request = ZMsg::recv(&mut self.frontend)?;
+        request.pushstr(&user_id)?;
+        request.pushstr("")?;
+        request.pushbytes(&frame_bytes(&router_id)?)?;
+
+        match self.idle.pop_front() {
+            Some(worker) => self.dispatch(&worker, request),
+            None => {
+                self.pending.push_back(request);
+                Ok(())
+            },
+        }
+    }
+
+    fn backend_recv(&mut self) -> Result<()> {
+        let msg = ZMsg::recv(&mut self.backend)?;
+        let worker = msg.popbytes()?.unwrap();
+        let first = msg.popbytes()?.unwrap_or_default();
+
+        if first.is_empty() {
+            match self.pending.pop_front() {
+                Some(request) => self.dispatch(&worker, request)?,
+                None => self.idle.push_back(worker),
+            }
+        } else {
+            msg.pushbytes(&first)?;
+            msg.send(&mut self.frontend)?;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, worker: &[u8], request: ZMsg) -> Result<()> {
+        request.pushbytes(worker)?;
+        request.send(&mut self.backend)?;
+        Ok(())
+    }
+}
+
+impl Endpoint for Server {
+    fn get_sockets(&mut self) -> Vec<&mut ZSock> {
+        vec![&mut self.frontend, &mut self.backend]
+    }
+
+    fn recv(&mut self, sock: &mut ZSock) -> StdResult<(), DError> {
+        let result = if sock.borrow_raw() == self.frontend.borrow_raw() {
+            self.frontend_recv()
+        } else {
+            self.backend_recv()
+        };
+        result.map_err(|e| e.into())
+    }
+}
+
+/// Dispatches requests to API handlers on a single worker thread.
+pub struct Worker {
+    sock: ZSock,
+    handlers: HashMap<String, Handler>,
+}
+
+impl Worker {
+    pub fn new(sock: ZSock) -> Worker {
+        Worker {
+            sock: sock,
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn add<F>(&mut self, name: &str, handler: F)
+        where F: FnMut(&mut ZSock, ZFrame, Option<Vec<u8>>) -> StdResult<(), DError> + 'static
+    {
+        self.handlers.insert(name.into(), Box::new(handler));
+    }
+
+    /// Handles requests until the socket is closed.
+    pub fn run(&mut self) {
+        // Requests arrive as [router_id, "", user_id, endpoint, args...]
+        while self.ready().is_ok() {
+            match ZFrame::recv(&mut self.sock) {
+                // Handlers report their own errors to the client
+                Ok(router_id) => { let _ = self.dispatch(router_id); },
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn ready(&mut self) -> Result<()> {
+        let msg = ZMsg::new();
+        msg.addstr("")?;
+        msg.send(&mut self.sock)?;
+        Ok(())
+    }
+
+    fn dispatch(&mut self, router_id: ZFrame) -> Result<()> {
+        let router_id = frame_bytes(&router_id)?;
+        ZFrame::recv(&mut self.sock)?;
+        let user_id = ZFrame::recv(&mut self.sock)?;
+        let frame = ZFrame::recv(&mut self.sock)?;
+
+        let handler = match frame.data()? {
+            Ok(name) => self.handlers.get_mut(&name).ok_or(Error::InvalidArg(format!("Unknown endpoint: {}", name))),
+            Err(_) => Err(Error::MessageUtf8),
+        };
+        match handler {
+            Ok(handler) => handler(&mut self.sock, user_id, Some(router_id))?,
+            Err(e) => {
+                // Discard the rest of the request so the next one starts
+                // on a fresh message
+                if frame.more() {
+                    ZMsg::recv(&mut self.sock)?;
+                }
+                error_handler(&mut self.sock, Err(e), &router_id)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+fn frame_bytes(frame: &ZFrame) -> Result<Vec<u8>> {
+    Ok(match frame.data()? {
+        Ok(s) => s.into_bytes(),
+        Err(b) => b,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use czmq::{SocketType, ZFrame, ZMsg, ZSock};
+    use error::Result;
+    use std::sync::mpsc;
+    use std::thread;
+    use super::{Server, Worker};
+    use super::super::{error_handler, user_id};
+    use zdaemon::ZMsgExtended;
+
+    fn echo(sock: &mut ZSock, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&request.popstr().unwrap().unwrap())?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    fn whoami(sock: &mut ZSock, frame: ZFrame, router_id: &[u8]) -> Result<()> {
+        ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&user_id(&frame).unwrap_or_default())?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    // Stands in for the ZAP handler, authenticating every client as
+    // "alice".
+    fn zap_handler() {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut zap = ZSock::new(SocketType::REP);
+            zap.bind("inproc://zeromq.zap.01").unwrap();
+            tx.send(()).unwrap();
+            loop {
+                let request = ZMsg::recv(&mut zap).unwrap();
+                let version = request.popstr().unwrap().unwrap();
+                let sequence = request.popstr().unwrap().unwrap();
+                let reply = ZMsg::new();
+                for frame in &[version.as_str(), sequence.as_str(), "200", "OK", "alice", ""] {
+                    reply.addstr(frame).unwrap();
+                }
+                reply.send(&mut zap).unwrap();
+            }
+        });
+        rx.recv().unwrap();
+    }
+
+    fn request(client: &mut ZSock, frames: &[&[u8]]) {
+        let msg = ZMsg::new();
+        for frame in frames {
+            msg.addbytes(frame).unwrap();
+        }
+        msg.send(client).unwrap();
+    }
+
+    fn reply(client: &mut ZSock) -> Vec<String> {
+        let msg = ZMsg::recv(client).unwrap();
+        let mut frames = Vec::new();
+        while let Some(f) = msg.popstr() {
+            frames.push(f.unwrap());
+        }
+        frames
+    }
+
+    #[test]
+    fn test_server() {
+        // Metadata is only set by the ZMTP handshake, so the frontend
+        // can't use inproc
+        zap_handler();
+        let mut frontend = ZSock::new(SocketType::ROUTER);
+        frontend.set_zap_domain("test_server");
+        let port = frontend.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = format!("tcp://127.0.0.1:{}", port);
+        let mut backend = ZSock::new(SocketType::ROUTER);
+        backend.bind("inproc://test_server_backend").unwrap();
+        let mut server = Server::new(frontend, backend);
+
+        thread::spawn(|| {
+            let mut sock = ZSock::new(SocketType::DEALER);
+            sock.connect("inproc://test_server_backend").unwrap();
+            let mut worker = Worker::new(sock);
+            worker.add("echo", |sock: &mut ZSock, _: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = echo(sock, &i); error_handler(sock, r, &i) });
+            worker.add("whoami", |sock: &mut ZSock, frame: ZFrame, id: Option<Vec<u8>>| { let i = id.unwrap(); let r = whoami(sock, frame, &i); error_handler(sock, r, &i) });
+            worker.run();
+        });
+
+        let mut client_a = ZSock::new(SocketType::REQ);
+        client_a.connect(&endpoint).unwrap();
+        let mut client_b = ZSock::new(SocketType::REQ);
+        client_b.connect(&endpoint).unwrap();
+
+        // Worker is idle
+        server.backend_recv().unwrap();
+
+        // The second request queues until the only worker is free
+        request(&mut client_a, &[b"echo", b"a"]);
+        request(&mut client_b, &[b"echo", b"b"]);
+        server.frontend_recv().unwrap();
+        server.frontend_recv().unwrap();
+        assert_eq!(server.pending.len(), 1);
+        for _ in 0..4 {
+            server.backend_recv().unwrap();
+        }
+        assert_eq!(server.idle.len(), 1);
+        assert_eq!(reply(&mut client_a), vec!["Ok", "a"]);
+        assert_eq!(reply(&mut client_b), vec!["Ok", "b"]);
+
+        request(&mut client_a, &[b"nope", b"x"]);
+        server.frontend_recv().unwrap();
+        server.backend_recv().unwrap();
+        server.backend_recv().unwrap();
+        assert_eq!(reply(&mut client_a)[0], "Err");
+
+        // The worker stays in sync after a non-UTF8 endpoint name
+        request(&mut client_b, &[b"\xff", b"x"]);
+        server.frontend_recv().unwrap();
+        server.backend_recv().unwrap();
+        server.backend_recv().unwrap();
+        assert_eq!(reply(&mut client_b)[0], "Err");
+
+        request(&mut client_b, &[b"echo", b"c"]);
+        server.frontend_recv().unwrap();
+        server.backend_recv().unwrap();
+        server.backend_recv().unwrap();
+        assert_eq!(reply(&mut client_b), vec!["Ok", "c"]);
+
+        // The client's identity reaches the handler
+        request(&mut client_a, &[b"whoami", b"x"]);
+        server.frontend_recv().unwrap();
+        server.backend_recv().unwrap();
+        server.backend_recv().unwrap();
+        assert_eq!(reply(&mut client_a), vec!["Ok", "alice"]);
+    }
+}
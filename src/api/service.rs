@@ -8,9 +8,90 @@
 
 use czmq::{ZMsg, ZSock};
 use error::{Error, Result};
-use inapi::{Service, ServiceRunnable, Host};
+use glob::{glob, Pattern};
+use inapi::{Command, Service, ServiceRunnable, Host};
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use super::{check_name, shell_quote, write_if_changed};
 use zdaemon::ZMsgExtended;
 
+/// Scripts in /etc/init.d that aren't services, even where they have an
+/// LSB header.
+const NOT_SERVICES: &'static [&'static str] = &["halt", "killall", "rc", "rc.local", "rcS", "reboot", "sendsigs", "single", "skeleton", "umountfs", "umountroot"];
+
+/// The init systems the agent ships service scripts for. See
+/// `resources/init`.
+#[derive(Debug, PartialEq)]
+enum InitSystem {
+    Debian,
+    Rc,
+    Redhat,
+    Systemd,
+}
+
+impl InitSystem {
+    // Detection mirrors the order used by `make install`
+    fn detect() -> Result<InitSystem> {
+        if Path::new("/etc/rc.conf").is_file() {
+            Ok(InitSystem::Rc)
+        }
+        else if Path::new("/run/systemd/system").is_dir() {
+            Ok(InitSystem::Systemd)
+        }
+        else if Path::new("/etc/redhat-release").is_file() {
+            Ok(InitSystem::Redhat)
+        }
+        else if Path::new("/etc/debian_version").is_file() {
+            Ok(InitSystem::Debian)
+        } else {
+            Err(Error::Unsupported("Could not detect a supported init system".into()))
+        }
+    }
+
+    // Where locally installed units live and the mode they need
+    fn unit_path(&self, name: &str) -> Result<(PathBuf, u32)> {
+        let name = check_name(name)?;
+        Ok(match *self {
+            InitSystem::Debian | InitSystem::Redhat => (Path::new("/etc/init.d").join(name), 0o755),
+            InitSystem::Rc => (Path::new("/usr/local/etc/rc.d").join(name), 0o555),
+            InitSystem::Systemd => (Path::new("/etc/systemd/system").join(format!("{}.service", name)), 0o644),
+        })
+    }
+
+    // Only systemd caches unit definitions. The other init systems read
+    // their scripts from disk on each invocation.
+    fn reload_cmd(&self) -> Option<&'static str> {
+        match *self {
+            InitSystem::Systemd => Some("systemctl daemon-reload"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+enum ServiceState {
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "stopped")]
+    Stopped,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+struct ServiceStatus {
+    name: String,
+    state: ServiceState,
+    pid: Option<u32>,
+    enabled: bool,
+}
+
 pub struct ServiceApi;
 
 impl ServiceApi {
@@ -34,4 +115,469 @@ impl ServiceApi {
         }
         Ok(())
     }
+
+    pub fn status(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let status = get_status(host, &InitSystem::detect()?, &name)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&status)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn list(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let services = list_services(host, &InitSystem::detect()?)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(&serde_json::to_string(&services)?)?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn install_unit(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 2, Some(2), false)?;
+        let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let contents = request.popbytes()?.unwrap();
+
+        let init = InitSystem::detect()?;
+        let (path, mode) = init.unit_path(&name)?;
+        let changed = write_if_changed(&path, &contents, Some(mode))?;
+        if changed {
+            reload(host, &init)?;
+        }
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if changed { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn remove_unit(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+
+        let init = InitSystem::detect()?;
+        let (path, _) = init.unit_path(&name)?;
+        let changed = path.exists();
+        if changed {
+            // Stop and deregister the service first, or it would be
+            // left running with dangling boot links.
+            for cmd in &[stop_cmd(&init, &name), deregister_cmd(&init, &name)] {
+                let result = Command::new(cmd).exec(host)?;
+                if result.exit_code != 0 {
+                    return Err(Error::CommandFailed(result.stderr));
+                }
+            }
+            fs::remove_file(&path)?;
+            reload(host, &init)?;
+        }
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if changed { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+
+    pub fn enable(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        set_enabled(sock, host, router_id, true)
+    }
+
+    pub fn disable(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        set_enabled(sock, host, router_id, false)
+    }
+
+    pub fn is_enabled(sock: &mut ZSock, host: &mut Host, router_id: &[u8]) -> Result<()> {
+        let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+        let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+        let enabled = is_enabled(host, &InitSystem::detect()?, &name)?;
+
+        let msg = ZMsg::new_ok()?;
+        msg.pushstr("")?;
+        msg.pushbytes(router_id)?;
+        msg.addstr(if enabled { "1" } else { "0" })?;
+        msg.send(sock)?;
+        Ok(())
+    }
+}
+
+fn list_services(host: &mut Host, init: &InitSystem) -> Result<Vec<ServiceStatus>> {
+    let names = match *init {
+        InitSystem::Systemd => {
+            let files = Command::new("systemctl list-unit-files --type=service --no-legend --no-pager").exec(host)?;
+            let units = Command::new("systemctl list-units --type=service --all --no-legend --no-pager").exec(host)?;
+            if files.exit_code != 0 || units.exit_code != 0 {
+                return Err(Error::CommandFailed(format!("{}{}", files.stderr, units.stderr)));
+            }
+            return Ok(parse_systemd_list(&files.stdout, &units.stdout));
+        },
+        InitSystem::Debian | InitSystem::Redhat => {
+            let mut names = Vec::new();
+            for entry in fs::read_dir("/etc/init.d")? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+                    if let Some(name) = entry.file_name().to_str() {
+                        // Binaries and unreadable files aren't services
+                        let mut script = String::new();
+                        if fs::File::open(entry.path()).and_then(|mut fh| fh.read_to_string(&mut script)).is_ok() &&
+                           is_service_script(name, &script) {
+                            names.push(name.to_string());
+                        }
+                    }
+                }
+            }
+            names
+        },
+        InitSystem::Rc => {
+            let result = Command::new("service -l").exec(host)?;
+            if result.exit_code != 0 {
+                return Err(Error::CommandFailed(result.stderr));
+            }
+            result.stdout.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect()
+        },
+    };
+
+    let mut services = Vec::new();
+    for name in names {
+        services.push(get_status(host, init, &name)?);
+    }
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(services)
+}
+
+// Unit files tell us what is enabled; loaded units tell us what is
+// running. Neither list is a superset of the other.
+fn parse_systemd_list(files: &str, units: &str) -> Vec<ServiceStatus> {
+    let mut services = HashMap::new();
+
+    for line in files.lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() >= 2 && fields[0].ends_with(".service") {
+            let name = fields[0].trim_right_matches(".service").to_string();
+            services.insert(name.clone(), ServiceStatus {
+                name: name,
+                state: ServiceState::Stopped,
+                pid: None,
+                enabled: fields[1] == "enabled",
+            });
+        }
+    }
+
+    // name.service loaded active running Description...
+    for line in units.lines() {
+        let fields: Vec<_> = line.trim_left_matches(|c| c == '*' || c == '●').split_whitespace().collect();
+        if fields.len() >= 4 && fields[0].ends_with(".service") {
+            let name = fields[0].trim_right_matches(".service").to_string();
+            let state = systemd_state(fields[2]);
+            services.entry(name.clone()).or_insert(ServiceStatus {
+                name: name,
+                state: ServiceState::Unknown,
+                pid: None,
+                enabled: false,
+            }).state = state;
+        }
+    }
+
+    let mut services: Vec<_> = services.into_iter().map(|(_, v)| v).collect();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+    services
+}
+
+fn reload(host: &mut Host, init: &InitSystem) -> Result<()> {
+    if let Some(cmd) = init.reload_cmd() {
+        let result = Command::new(cmd).exec(host)?;
+        if result.exit_code != 0 {
+            return Err(Error::CommandFailed(result.stderr));
+        }
+    }
+    Ok(())
+}
+
+fn set_enabled(sock: &mut ZSock, host: &mut Host, router_id: &[u8], enable: bool) -> Result<()> {
+    let request = ZMsg::expect_recv(sock, 1, Some(1), false)?;
+    let name = request.popstr().unwrap().or(Err(Error::MessageUtf8))?;
+
+    let init = InitSystem::detect()?;
+    let changed = is_enabled(host, &init, &name)? != enable;
+    if changed {
+        let result = Command::new(&enable_cmd(&init, &name, enable)).exec(host)?;
+        if result.exit_code != 0 {
+            return Err(Error::CommandFailed(result.stderr));
+        }
+    }
+
+    let msg = ZMsg::new_ok()?;
+    msg.pushstr("")?;
+    msg.pushbytes(router_id)?;
+    msg.addstr(if changed { "1" } else { "0" })?;
+    msg.send(sock)?;
+    Ok(())
+}
+
+fn enable_cmd(init: &InitSystem, name: &str, enable: bool) -> String {
+    let name = shell_quote(name);
+    match (init, enable) {
+        (&InitSystem::Debian, true) => format!("update-rc.d {0} defaults && update-rc.d {0} enable", name),
+        (&InitSystem::Debian, false) => format!("update-rc.d {} disable", name),
+        (&InitSystem::Rc, true) => format!("sysrc {}_enable=YES", name),
+        (&InitSystem::Rc, false) => format!("sysrc {}_enable=NO", name),
+        (&InitSystem::Redhat, true) => format!("chkconfig {} on", name),
+        (&InitSystem::Redhat, false) => format!("chkconfig {} off", name),
+        (&InitSystem::Systemd, true) => format!("systemctl enable {}", name),
+        (&InitSystem::Systemd, false) => format!("systemctl disable {}", name),
+    }
+}
+
+fn stop_cmd(init: &InitSystem, name: &str) -> String {
+    let name = shell_quote(name);
+    match *init {
+        InitSystem::Debian | InitSystem::Redhat => format!("service {} stop", name),
+        InitSystem::Rc => format!("service {} onestop", name),
+        InitSystem::Systemd => format!("systemctl stop {}", name),
+    }
+}
+
+// Removes every trace of the service from the boot sequence, unlike
+// `enable_cmd`, which leaves links behind to record it as disabled.
+fn deregister_cmd(init: &InitSystem, name: &str) -> String {
+    let name = shell_quote(name);
+    match *init {
+        InitSystem::Debian => format!("update-rc.d -f {} remove", name),
+        // sysrc fails if the variable was never set
+        InitSystem::Rc => format!("sysrc -x {}_enable || true", name),
+        InitSystem::Redhat => format!("chkconfig --del {}", name),
+        InitSystem::Systemd => format!("systemctl disable {}", name),
+    }
+}
+
+fn is_enabled(host: &mut Host, init: &InitSystem, name: &str) -> Result<bool> {
+    match *init {
+        InitSystem::Debian | InitSystem::Redhat => sysv_enabled(name),
+        InitSystem::Rc => Ok(Command::new(&format!("service {} enabled", shell_quote(name))).exec(host)?.exit_code == 0),
+        // Uses the same definition as `get_status`. `systemctl
+        // is-enabled` also succeeds for static and alias units, which
+        // can't be enabled or disabled.
+        InitSystem::Systemd => {
+            let result = Command::new(&format!("systemctl show -p LoadState -p UnitFileState {}", shell_quote(name))).exec(host)?;
+            if result.exit_code != 0 {
+                return Err(Error::CommandFailed(result.stderr));
+            }
+            Ok(parse_systemd_show(name, &result.stdout)?.enabled)
+        },
+    }
+}
+
+fn get_status(host: &mut Host, init: &InitSystem, name: &str) -> Result<ServiceStatus> {
+    match *init {
+        InitSystem::Systemd => {
+            let result = Command::new(&format!("systemctl show -p LoadState -p ActiveState -p MainPID -p UnitFileState {}", shell_quote(name))).exec(host)?;
+            if result.exit_code != 0 {
+                return Err(Error::CommandFailed(result.stderr));
+            }
+            parse_systemd_show(name, &result.stdout)
+        },
+        InitSystem::Debian | InitSystem::Redhat => {
+            // `service` reports unknown services as failed, so check
+            // the script exists before trusting its exit code.
+            check_script(&[Path::new("/etc/init.d")], name)?;
+            let result = Command::new(&format!("service {} status", shell_quote(name))).exec(host)?;
+            let state = lsb_state(result.exit_code);
+            Ok(ServiceStatus {
+                name: name.into(),
+                pid: if state == ServiceState::Running { read_pidfile(&format!("/var/run/{}.pid", name)) } else { None },
+                state: state,
+                enabled: is_enabled(host, init, name)?,
+            })
+        },
+        InitSystem::Rc => {
+            check_script(&[Path::new("/etc/rc.d"), Path::new("/usr/local/etc/rc.d")], name)?;
+            let result = Command::new(&format!("service {} onestatus", shell_quote(name))).exec(host)?;
+            Ok(ServiceStatus {
+                name: name.into(),
+                state: if result.exit_code == 0 { ServiceState::Running } else { ServiceState::Stopped },
+                // "inagent is running as pid 1234."
+                pid: result.stdout.split_whitespace().last().and_then(|p| p.trim_right_matches('.').parse().ok()),
+                enabled: is_enabled(host, init, name)?,
+            })
+        },
+    }
+}
+
+// `systemctl show` succeeds for units that don't exist, reporting
+// LoadState=not-found.
+fn parse_systemd_show(name: &str, output: &str) -> Result<ServiceStatus> {
+    let mut status = ServiceStatus {
+        name: name.into(),
+        state: ServiceState::Unknown,
+        pid: None,
+        enabled: false,
+    };
+
+    for line in output.lines() {
+        let mut kv = line.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some("LoadState"), Some("not-found")) => return Err(Error::InvalidArg(format!("Unknown service: {}", name))),
+            (Some("ActiveState"), Some(v)) => status.state = systemd_state(v),
+            (Some("MainPID"), Some(v)) => status.pid = v.parse().ok().and_then(|p| if p > 0 { Some(p) } else { None }),
+            (Some("UnitFileState"), Some(v)) => status.enabled = v == "enabled",
+            _ => (),
+        }
+    }
+
+    Ok(status)
+}
+
+fn systemd_state(active_state: &str) -> ServiceState {
+    match active_state {
+        "active" | "reloading" => ServiceState::Running,
+        "inactive" => ServiceState::Stopped,
+        "failed" => ServiceState::Failed,
+        _ => ServiceState::Unknown,
+    }
+}
+
+// See LSB init script actions for `status`
+fn lsb_state(exit_code: i32) -> ServiceState {
+    match exit_code {
+        0 => ServiceState::Running,
+        1 | 2 => ServiceState::Failed,
+        3 => ServiceState::Stopped,
+        _ => ServiceState::Unknown,
+    }
+}
+
+fn check_script(dirs: &[&Path], name: &str) -> Result<()> {
+    let name = check_name(name)?;
+    if dirs.iter().any(|d| d.join(name).is_file()) {
+        Ok(())
+    } else {
+        Err(Error::InvalidArg(format!("Unknown service: {}", name)))
+    }
+}
+
+// init.d also holds the scripts that drive runlevel changes, which
+// mustn't be run with `status`.
+fn is_service_script(name: &str, script: &str) -> bool {
+    !NOT_SERVICES.contains(&name) && script.contains("### BEGIN INIT INFO")
+}
+
+fn sysv_enabled(name: &str) -> Result<bool> {
+    let pattern = format!("/etc/rc[2-5].d/S[0-9][0-9]{}", Pattern::escape(check_name(name)?));
+    Ok(glob(&pattern)?.any(|p| p.is_ok()))
+}
+
+fn read_pidfile(path: &str) -> Option<u32> {
+    let mut pid = String::new();
+    match fs::File::open(path).and_then(|mut fh| fh.read_to_string(&mut pid)) {
+        Ok(_) => pid.trim().parse().ok(),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::{check_script, deregister_cmd, enable_cmd, is_service_script, lsb_state, sysv_enabled, stop_cmd, parse_systemd_list, parse_systemd_show, InitSystem, ServiceState};
+
+    #[test]
+    fn test_parse_systemd_show() {
+        let status = parse_systemd_show("nginx", "LoadState=loaded\nActiveState=active\nMainPID=1234\nUnitFileState=enabled\n").unwrap();
+        assert_eq!(status.state, ServiceState::Running);
+        assert_eq!(status.pid, Some(1234));
+        assert!(status.enabled);
+
+        let status = parse_systemd_show("nginx", "LoadState=loaded\nActiveState=failed\nMainPID=0\nUnitFileState=disabled\n").unwrap();
+        assert_eq!(status.state, ServiceState::Failed);
+        assert!(status.pid.is_none());
+        assert!(!status.enabled);
+
+        assert!(parse_systemd_show("nope", "LoadState=not-found\nActiveState=inactive\nMainPID=0\nUnitFileState=\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_systemd_list() {
+        let files = "nginx.service enabled\nsshd.service disabled\nbasic.target static\n";
+        let units = "nginx.service loaded active running A high performance web server\n\u{25cf} sshd.service loaded failed failed OpenSSH\nsystemd-journald.service loaded active running Journal Service\n";
+        let services = parse_systemd_list(files, units);
+        assert_eq!(services.len(), 3);
+        assert_eq!(services[0].name, "nginx");
+        assert_eq!(services[0].state, ServiceState::Running);
+        assert!(services[0].enabled);
+        assert_eq!(services[1].name, "sshd");
+        assert_eq!(services[1].state, ServiceState::Failed);
+        assert!(!services[1].enabled);
+        assert_eq!(services[2].name, "systemd-journald");
+    }
+
+    #[test]
+    fn test_enable_cmd() {
+        assert_eq!(enable_cmd(&InitSystem::Systemd, "nginx", true), "systemctl enable 'nginx'");
+        assert_eq!(enable_cmd(&InitSystem::Redhat, "nginx", false), "chkconfig 'nginx' off");
+        assert_eq!(enable_cmd(&InitSystem::Debian, "nginx", true), "update-rc.d 'nginx' defaults && update-rc.d 'nginx' enable");
+    }
+
+    #[test]
+    fn test_unit_path() {
+        assert_eq!(InitSystem::Debian.unit_path("inagent").unwrap(), (Path::new("/etc/init.d/inagent").to_owned(), 0o755));
+        assert_eq!(InitSystem::Redhat.unit_path("inagent").unwrap(), (Path::new("/etc/init.d/inagent").to_owned(), 0o755));
+        assert_eq!(InitSystem::Rc.unit_path("inagent").unwrap(), (Path::new("/usr/local/etc/rc.d/inagent").to_owned(), 0o555));
+        assert_eq!(InitSystem::Systemd.unit_path("inagent").unwrap(), (Path::new("/etc/systemd/system/inagent.service").to_owned(), 0o644));
+        assert!(InitSystem::Systemd.unit_path("../inagent").is_err());
+    }
+
+    #[test]
+    fn test_reload_cmd() {
+        assert_eq!(InitSystem::Systemd.reload_cmd(), Some("systemctl daemon-reload"));
+        assert!(InitSystem::Debian.reload_cmd().is_none());
+        assert!(InitSystem::Rc.reload_cmd().is_none());
+    }
+
+    #[test]
+    fn test_remove_cmds() {
+        assert_eq!(stop_cmd(&InitSystem::Systemd, "inagent"), "systemctl stop 'inagent'");
+        assert_eq!(stop_cmd(&InitSystem::Rc, "inagent"), "service 'inagent' onestop");
+        assert_eq!(deregister_cmd(&InitSystem::Systemd, "inagent"), "systemctl disable 'inagent'");
+        assert_eq!(deregister_cmd(&InitSystem::Debian, "inagent"), "update-rc.d -f 'inagent' remove");
+        assert_eq!(deregister_cmd(&InitSystem::Redhat, "inagent"), "chkconfig --del 'inagent'");
+    }
+
+    #[test]
+    fn test_check_script() {
+        assert!(check_script(&[Path::new("/nonexistent"), Path::new("/bin")], "sh").is_ok());
+        assert!(check_script(&[Path::new("/bin")], "no_such_service_inagent").is_err());
+        assert!(check_script(&[Path::new("/")], "../bin/sh").is_err());
+    }
+
+    #[test]
+    fn test_sysv_enabled() {
+        assert!(!sysv_enabled("*").unwrap());
+        assert!(sysv_enabled("../init.d/x").is_err());
+    }
+
+    #[test]
+    fn test_is_service_script() {
+        let lsb = "#!/bin/sh\n### BEGIN INIT INFO\n# Provides: nginx\n### END INIT INFO\n";
+        assert!(is_service_script("nginx", lsb));
+        assert!(!is_service_script("nginx", "#!/bin/sh\necho hello\n"));
+        assert!(!is_service_script("rcS", lsb));
+        assert!(!is_service_script("halt", lsb));
+    }
+
+    #[test]
+    fn test_lsb_state() {
+        assert_eq!(lsb_state(0), ServiceState::Running);
+        assert_eq!(lsb_state(3), ServiceState::Stopped);
+        assert_eq!(lsb_state(1), ServiceState::Failed);
+        assert_eq!(lsb_state(4), ServiceState::Unknown);
+    }
 }
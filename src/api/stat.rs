@@ -0,0 +1,149 @@
+// Copyright 2015-2017 Intecture Developers. See the COPYRIGHT file at the
+// top-level directory of this distribution and at
+// https://intecture.io/COPYRIGHT.
+//
+// Licensed under the Mozilla Public License 2.0 <LICENSE or
+// https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
+// modified, or distributed except according to those terms.
+
+use error::Result;
+use libc;
+use std::ffi::CStr;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::{mem, ptr};
+
+/// Metadata for a path. Symlinks are followed, except for `symlink` and
+/// `target`, unless they're dangling.
+#[derive(Debug, Serialize)]
+pub struct Stat {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub file_type: &'static str,
+    pub size: u64,
+    pub mtime: i64,
+    pub inode: u64,
+    pub nlink: u64,
+    /// Permission bits in octal, e.g. "755"
+    pub mode: String,
+    pub user_name: Option<String>,
+    pub user_uid: u32,
+    pub group_name: Option<String>,
+    pub group_gid: u32,
+    pub symlink: bool,
+    pub target: Option<String>,
+}
+
+impl Stat {
+    pub fn new(path: &Path) -> Result<Stat> {
+        let link_meta = fs::symlink_metadata(path)?;
+        let symlink = link_meta.file_type().is_symlink();
+        let target = if symlink {
+            Some(fs::read_link(path)?.to_string_lossy().into_owned())
+        } else {
+            None
+        };
+        let meta = if symlink { fs::metadata(path).unwrap_or(link_meta) } else { link_meta };
+
+        Ok(Stat {
+            path: path.to_string_lossy().into_owned(),
+            file_type: file_type(&meta.file_type()),
+            size: meta.size(),
+            mtime: meta.mtime(),
+            inode: meta.ino(),
+            nlink: meta.nlink(),
+            mode: format!("{:o}", meta.mode() & 0o7777),
+            user_name: user_name(meta.uid()),
+            user_uid: meta.uid(),
+            group_name: group_name(meta.gid()),
+            group_gid: meta.gid(),
+            symlink: symlink,
+            target: target,
+        })
+    }
+}
+
+fn file_type(ft: &fs::FileType) -> &'static str {
+    if ft.is_file() {
+        "file"
+    } else if ft.is_dir() {
+        "directory"
+    } else if ft.is_symlink() {
+        "symlink"
+    } else if ft.is_fifo() {
+        "fifo"
+    } else if ft.is_socket() {
+        "socket"
+    } else if ft.is_block_device() {
+        "block_device"
+    } else if ft.is_char_device() {
+        "char_device"
+    } else {
+        "unknown"
+    }
+}
+
+fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+    let ret = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+
+    if ret != 0 || result.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(pwd.pw_name) }.to_string_lossy().into_owned())
+    }
+}
+
+fn group_name(gid: libc::gid_t) -> Option<String> {
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = ptr::null_mut();
+    let ret = unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+
+    if ret != 0 || result.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(grp.gr_name) }.to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use super::Stat;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_stat() {
+        let dir = TempDir::new("test_stat").unwrap();
+        let path = dir.path().join("file");
+        fs::File::create(&path).unwrap().write_all(b"abc").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let stat = Stat::new(&path).unwrap();
+        assert_eq!(stat.file_type, "file");
+        assert_eq!(stat.size, 3);
+        assert_eq!(stat.mode, "640");
+        assert_eq!(stat.nlink, 1);
+        assert!(!stat.symlink);
+        assert!(stat.target.is_none());
+
+        let link = dir.path().join("link");
+        symlink(&path, &link).unwrap();
+        let stat = Stat::new(&link).unwrap();
+        assert_eq!(stat.file_type, "file");
+        assert!(stat.symlink);
+        assert_eq!(stat.target.unwrap(), path.to_str().unwrap());
+
+        let dangling = dir.path().join("dangling");
+        symlink(dir.path().join("missing"), &dangling).unwrap();
+        assert_eq!(Stat::new(&dangling).unwrap().file_type, "symlink");
+
+        assert_eq!(Stat::new(dir.path()).unwrap().file_type, "directory");
+    }
+}
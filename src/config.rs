@@ -6,6 +6,8 @@
 // https://www.tldrlegal.com/l/mpl-2.0>. This file may not be copied,
 // modified, or distributed except according to those terms.
 
+use std::collections::HashMap;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub api_port: u32,
@@ -15,4 +17,112 @@ pub struct Config {
     pub auth_server: String,
     pub auth_update_port: u32,
     pub auth_cert: String,
+    /// Number of threads handling API requests
+    #[serde(default = "default_api_threads")]
+    pub api_threads: u32,
+    /// Seconds to keep the results of finished jobs
+    #[serde(default = "default_job_retention")]
+    pub job_retention: u64,
+    /// Resource limits for executed commands, used wherever a request
+    /// doesn't set its own
+    #[serde(default)]
+    pub exec_limits: Limits,
+    /// Restricts which commands clients may run. Unrestricted if
+    /// omitted.
+    #[serde(default)]
+    pub command_policy: CommandPolicy,
+}
+
+/// Rules are matched against the whole command string. As
+/// `command::exec` runs it through a shell, `prefix` rules and unanchored
+/// `regex` rules can be bypassed with shell metacharacters, e.g.
+/// "uptime; rm -rf /" matches the prefix "uptime", unless a deny rule
+/// rejects them, such as the regex "[;&|`$<>()\n]". Only `exact` rules
+/// pin down the entire command.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CommandPolicy {
+    #[serde(default)]
+    pub allow: Vec<PolicyRule>,
+    /// Applies to every client, whether or not it has its own rules
+    #[serde(default)]
+    pub deny: Vec<PolicyRule>,
+    /// Rules for specific client certificates, keyed by ZAP user id.
+    /// Their allow rules replace the global allow rules, unless there
+    /// are none, and their deny rules add to the global deny rules.
+    #[serde(default)]
+    pub identities: HashMap<String, PolicyRules>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PolicyRules {
+    #[serde(default)]
+    pub allow: Vec<PolicyRule>,
+    #[serde(default)]
+    pub deny: Vec<PolicyRule>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PolicyRule {
+    #[serde(rename = "exact")]
+    Exact(String),
+    #[serde(rename = "prefix")]
+    Prefix(String),
+    #[serde(rename = "regex")]
+    Regex(String),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Limits {
+    /// CPU time in seconds
+    pub cpu: Option<u64>,
+    /// Address space in bytes
+    pub address_space: Option<u64>,
+    pub open_files: Option<u64>,
+    pub processes: Option<u64>,
+    /// Scheduling priority, from -20 to 19
+    pub nice: Option<i32>,
+    /// Best effort IO priority, from 0 (highest) to 7. Linux only.
+    pub ionice: Option<u32>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.cpu.is_none() && self.address_space.is_none() && self.open_files.is_none() &&
+            self.processes.is_none() && self.nice.is_none() && self.ionice.is_none()
+    }
+
+    /// Fills any limits missing from `self` with those in `defaults`.
+    pub fn merge(&self, defaults: &Limits) -> Limits {
+        Limits {
+            cpu: self.cpu.or(defaults.cpu),
+            address_space: self.address_space.or(defaults.address_space),
+            open_files: self.open_files.or(defaults.open_files),
+            processes: self.processes.or(defaults.processes),
+            nice: self.nice.or(defaults.nice),
+            ionice: self.ionice.or(defaults.ionice),
+        }
+    }
+}
+
+fn default_api_threads() -> u32 {
+    4
+}
+
+fn default_job_retention() -> u64 {
+    3600
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Limits;
+
+    #[test]
+    fn test_limits_merge() {
+        let defaults = Limits { cpu: Some(60), nice: Some(10), ..Default::default() };
+        let limits = Limits { nice: Some(0), open_files: Some(256), ..Default::default() }.merge(&defaults);
+        assert_eq!(limits.cpu, Some(60));
+        assert_eq!(limits.nice, Some(0));
+        assert_eq!(limits.open_files, Some(256));
+        assert!(limits.processes.is_none());
+    }
 }
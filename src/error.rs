@@ -7,9 +7,12 @@
 // modified, or distributed except according to those terms.
 
 use czmq;
+use glob;
 use inapi;
+use regex;
 use serde_json;
-use std::{convert, error, fmt, io, result};
+use std::{convert, error, fmt, io, num, result};
+use tempfile;
 use zdaemon;
 use zfilexfer;
 
@@ -17,11 +20,20 @@ pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    ApiWorker(String),
+    CommandFailed(String),
     Czmq(czmq::Error),
+    GlobPattern(glob::PatternError),
     Inapi(inapi::Error),
+    InvalidArg(String),
+    InvalidProvider(String),
     Io(io::Error),
     MessageUtf8,
+    ParseInt(num::ParseIntError),
+    Policy(String),
+    Regex(regex::Error),
     SerdeJson(serde_json::Error),
+    Unsupported(String),
     ZDaemon(zdaemon::Error),
     ZFileXfer(zfilexfer::Error),
 }
@@ -29,11 +41,20 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Error::ApiWorker(ref e) => write!(f, "API worker failed to start: {}", e),
+            Error::CommandFailed(ref e) => write!(f, "Command failed: {}", e),
             Error::Czmq(ref e) => write!(f, "CZMQ error: {}", e),
+            Error::GlobPattern(ref e) => write!(f, "Glob pattern error: {}", e),
             Error::Inapi(ref e) => write!(f, "Intecture API error: {}", e),
+            Error::InvalidArg(ref e) => write!(f, "Invalid argument: {}", e),
+            Error::InvalidProvider(ref p) => write!(f, "Invalid package provider: {}", p),
             Error::Io(ref e) => write!(f, "IO error: {}", e),
             Error::MessageUtf8 => write!(f, "Message is not UTF8 compatible"),
+            Error::ParseInt(ref e) => write!(f, "Integer parse error: {}", e),
+            Error::Policy(ref c) => write!(f, "Command rejected by policy: {}", c),
+            Error::Regex(ref e) => write!(f, "Regex error: {}", e),
             Error::SerdeJson(ref e) => write!(f, "Serde JSON error: {}", e),
+            Error::Unsupported(ref e) => write!(f, "Unsupported operation: {}", e),
             Error::ZDaemon(ref e) => write!(f, "ZDaemon error: {}", e),
             Error::ZFileXfer(ref e) => write!(f, "ZFileXfer error: {}", e),
         }
@@ -43,11 +64,20 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::ApiWorker(_) => "API worker failed to start",
+            Error::CommandFailed(_) => "Command failed",
             Error::Czmq(ref e) => e.description(),
+            Error::GlobPattern(ref e) => e.description(),
             Error::Inapi(ref e) => e.description(),
+            Error::InvalidArg(_) => "Invalid argument",
+            Error::InvalidProvider(_) => "Invalid package provider",
             Error::Io(ref e) => e.description(),
             Error::MessageUtf8 => "Message is not UTF8 compatible",
+            Error::ParseInt(ref e) => e.description(),
+            Error::Policy(_) => "Command rejected by policy",
+            Error::Regex(ref e) => e.description(),
             Error::SerdeJson(ref e) => e.description(),
+            Error::Unsupported(_) => "Unsupported operation",
             Error::ZDaemon(ref e) => e.description(),
             Error::ZFileXfer(ref e) => e.description(),
         }
@@ -60,6 +90,12 @@ impl convert::From<czmq::Error> for Error {
     }
 }
 
+impl convert::From<glob::PatternError> for Error {
+    fn from(err: glob::PatternError) -> Error {
+        Error::GlobPattern(err)
+    }
+}
+
 impl convert::From<inapi::Error> for Error {
     fn from(err: inapi::Error) -> Error {
         Error::Inapi(err)
@@ -72,6 +108,24 @@ impl convert::From<io::Error> for Error {
     }
 }
 
+impl convert::From<num::ParseIntError> for Error {
+    fn from(err: num::ParseIntError) -> Error {
+        Error::ParseInt(err)
+    }
+}
+
+impl convert::From<regex::Error> for Error {
+    fn from(err: regex::Error) -> Error {
+        Error::Regex(err)
+    }
+}
+
+impl convert::From<tempfile::PersistError> for Error {
+    fn from(err: tempfile::PersistError) -> Error {
+        Error::Io(err.error)
+    }
+}
+
 impl convert::From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
         Error::SerdeJson(err)
@@ -8,10 +8,14 @@
 
 extern crate chan;
 extern crate chan_signal;
+extern crate crypto;
 extern crate czmq;
 extern crate docopt;
+extern crate glob;
 extern crate inapi;
 extern crate inauth_client;
+extern crate libc;
+extern crate regex;
 extern crate rustc_serialize;
 extern crate serde;
 #[macro_use]
@@ -19,6 +23,7 @@ extern crate serde_derive;
 extern crate serde_json;
 #[cfg(test)]
 extern crate tempdir;
+extern crate tempfile;
 extern crate zdaemon;
 extern crate zfilexfer;
 
@@ -30,12 +35,13 @@ use chan_signal::Signal;
 use config::Config;
 use czmq::{ZCert, ZSock, SocketType, ZSys};
 use docopt::Docopt;
-use error::Result;
+use error::{Error, Result};
 use inauth_client::{CertType, ZapHandler};
 use std::{env, fs};
 use std::io::Read;
 use std::path::Path;
 use std::process::exit;
+use std::sync::Arc;
 use std::thread::spawn;
 use zdaemon::Service;
 use zfilexfer::Server as FileServer;
@@ -106,10 +112,11 @@ fn start<P: AsRef<Path>>(path: Option<P>) -> Result<()> {
     file_sock.set_linger(1000);
     try!(file_sock.bind(&format!("tcp://*:{}", config.filexfer_port)));
 
+    let config = Arc::new(config);
     let thread = spawn(move || {
         let mut service = Service::new(child).unwrap();
 
-        let api_endpoint = api::endpoint(config.api_port, &server_cert).unwrap();
+        let api_endpoint = api::endpoint(config.clone(), &server_cert).unwrap();
         service.add_endpoint(api_endpoint).unwrap();
 
         let file_endpoint = FileServer::new(file_sock, config.filexfer_threads).unwrap();
@@ -149,7 +156,14 @@ fn do_read_conf<P: AsRef<Path>>(path: P) -> Result<Config> {
     let mut fh = fs::File::open(&path)?;
     let mut json = String::new();
     fh.read_to_string(&mut json)?;
-    Ok(serde_json::from_str(&json)?)
+    let config: Config = serde_json::from_str(&json)?;
+
+    // With no workers, API requests would never be answered
+    if config.api_threads == 0 {
+        return Err(Error::InvalidArg("api_threads must be at least 1".into()));
+    }
+
+    Ok(config)
 }
 
 #[cfg(test)]
@@ -183,4 +197,22 @@ mod tests {
         let none: Option<PathBuf> = None;
         assert!(read_conf(none).is_ok());
     }
+
+    #[test]
+    fn test_read_conf_api_threads() {
+        let tmpdir = TempDir::new("test_read_conf_api_threads").unwrap();
+        let mut fh = fs::File::create(tmpdir.path().join("agent.json")).unwrap();
+        fh.write_all(b"{
+            \"api_port\": 123,
+            \"api_threads\": 0,
+            \"server_cert\": \"\",
+            \"filexfer_port\": 123,
+            \"filexfer_threads\": 123,
+            \"auth_server\": \"\",
+            \"auth_update_port\": 123,
+            \"auth_cert\": \"\"
+        }").unwrap();
+
+        assert!(read_conf(Some(tmpdir.path())).is_err());
+    }
 }